@@ -0,0 +1,217 @@
+//! `#[derive(Loggable)]` for [sjl](https://docs.rs/sjl). Use it through sjl's `derive` feature.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{Data, DeriveInput, Field, Fields, LitInt, LitStr, parse_macro_input, parse_quote};
+
+/// Implements `sjl::Loggable` for a struct with named fields.
+///
+/// Field attributes:
+/// - `#[sjl(redact)]` logs `"[REDACTED]"` instead of the value
+/// - `#[sjl(skip)]` leaves the field out
+/// - `#[sjl(hash)]` logs a stable hash of the value
+/// - `#[sjl(truncate = 64)]` keeps the first 64 characters, the field has to be `AsRef<str>`
+/// - `#[sjl(rename = "name")]` logs the field under a different key
+///
+/// `#[serde(...)]` attributes are not looked at, the type's `Serialize` impl stays as it is.
+#[proc_macro_derive(Loggable, attributes(sjl))]
+pub fn derive_loggable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+enum Rule {
+    Plain,
+    Redact,
+    Skip,
+    Hash,
+    Truncate(usize),
+}
+
+struct FieldOptions {
+    rule: Rule,
+    rename: Option<LitStr>,
+}
+
+fn field_options(field: &Field) -> syn::Result<FieldOptions> {
+    let mut rule = None;
+    let mut rename = None;
+
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("sjl"))
+    {
+        attr.parse_nested_meta(|meta| {
+            let new_rule = if meta.path.is_ident("redact") {
+                Rule::Redact
+            } else if meta.path.is_ident("skip") {
+                Rule::Skip
+            } else if meta.path.is_ident("hash") {
+                Rule::Hash
+            } else if meta.path.is_ident("truncate") {
+                let max_chars: LitInt = meta.value()?.parse()?;
+                Rule::Truncate(max_chars.base10_parse()?)
+            } else if meta.path.is_ident("rename") {
+                rename = Some(meta.value()?.parse()?);
+                return Ok(());
+            } else {
+                return Err(meta.error(
+                    "unknown sjl attribute, expected one of: redact, skip, hash, truncate, rename",
+                ));
+            };
+
+            if rule.replace(new_rule).is_some() {
+                return Err(
+                    meta.error("only one of redact, skip, hash or truncate can be used per field")
+                );
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(FieldOptions {
+        rule: rule.unwrap_or(Rule::Plain),
+        rename,
+    })
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "Loggable can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "Loggable can only be derived for structs with named fields",
+        ));
+    };
+
+    let mut statements = Vec::new();
+    let mut bounds: Vec<syn::WherePredicate> = Vec::new();
+    for field in &fields.named {
+        let options = field_options(field)?;
+        let (Some(ident), ty) = (&field.ident, &field.ty) else {
+            continue;
+        };
+        let key = options
+            .rename
+            .map_or_else(|| ident.to_string(), |rename| rename.value());
+
+        let value = match options.rule {
+            Rule::Skip => continue,
+            Rule::Plain => {
+                bounds.push(parse_quote!(#ty: ::sjl::__private::Serialize));
+                quote!(&self.#ident)
+            }
+            Rule::Redact => quote!(::sjl::__private::REDACTED),
+            Rule::Hash => {
+                bounds.push(parse_quote!(#ty: ::sjl::__private::Serialize));
+                quote!(&::sjl::__private::Hashed(&self.#ident))
+            }
+            Rule::Truncate(max_chars) => {
+                bounds.push(parse_quote!(#ty: ::core::convert::AsRef<str>));
+                quote!(&::sjl::__private::Truncated {
+                    value: ::core::convert::AsRef::<str>::as_ref(&self.#ident),
+                    max_chars: #max_chars,
+                })
+            }
+        };
+        statements.push(quote! {
+            ::sjl::__private::SerializeStruct::serialize_field(&mut state, #key, #value)?;
+        });
+    }
+
+    let where_clause = input.generics.make_where_clause();
+    where_clause.predicates.extend(bounds);
+
+    let name = &input.ident;
+    let name_str = name.to_string();
+    let len = statements.len();
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::sjl::Loggable for #name #ty_generics #where_clause {
+            fn serialize_for_log<__S: ::sjl::__private::Serializer>(
+                &self,
+                serializer: __S,
+            ) -> ::core::result::Result<__S::Ok, __S::Error> {
+                let mut state = ::sjl::__private::Serializer::serialize_struct(serializer, #name_str, #len)?;
+                #(#statements)*
+                ::sjl::__private::SerializeStruct::end(state)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_err(input: DeriveInput) -> String {
+        expand(input)
+            .err()
+            .map(|e| e.to_string())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_rejects_unknown_attribute() {
+        let input = parse_quote! {
+            struct User {
+                #[sjl(encrypt)]
+                password: String,
+            }
+        };
+        assert!(expand_err(input).starts_with("unknown sjl attribute"));
+    }
+
+    #[test]
+    fn test_rejects_conflicting_rules() {
+        let input = parse_quote! {
+            struct User {
+                #[sjl(redact, hash)]
+                password: String,
+            }
+        };
+        assert!(expand_err(input).starts_with("only one of"));
+    }
+
+    #[test]
+    fn test_rejects_tuple_structs_and_enums() {
+        assert!(
+            expand_err(parse_quote!(
+                struct Id(u64);
+            ))
+            .contains("named fields")
+        );
+        assert!(
+            expand_err(parse_quote!(
+                enum Kind {
+                    A,
+                }
+            ))
+            .contains("only be derived for structs")
+        );
+    }
+
+    #[test]
+    fn test_skipped_fields_are_not_counted() {
+        let input = parse_quote! {
+            struct User {
+                name: String,
+                #[sjl(skip)]
+                avatar: Vec<u8>,
+            }
+        };
+        let expanded = expand(input).unwrap().to_string();
+        assert!(expanded.contains("\"User\" , 1usize"));
+        assert!(!expanded.contains("avatar"));
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::log_level::LogLevel;
+
+pub(crate) const BACKFILL_KEY: &str = "backfill";
+
+/// Keeps the last `capacity` events below `min_level` as encoded lines, writing them out
+/// only once something at or above `trigger` is logged.
+pub(crate) struct Backfill {
+    pub(crate) trigger: LogLevel,
+    capacity: usize,
+    lines: Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl Backfill {
+    pub(crate) fn new(capacity: usize, trigger: LogLevel) -> Self {
+        Backfill {
+            trigger,
+            capacity,
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub(crate) fn is_triggered_by(&self, level: LogLevel) -> bool {
+        level.severity() >= self.trigger.severity()
+    }
+
+    /// Hands back the oldest line if the buffer was full so its allocation can be reused
+    pub(crate) fn push(&self, line: Vec<u8>) -> Option<Vec<u8>> {
+        // A panic while holding the lock can't leave a half written line in the buffer
+        let mut lines = self.lines.lock().unwrap_or_else(PoisonError::into_inner);
+        let evicted = if lines.len() >= self.capacity {
+            lines.pop_front()
+        } else {
+            None
+        };
+        lines.push_back(line);
+        evicted
+    }
+
+    /// Everything buffered so far, oldest first
+    pub(crate) fn take(&self) -> VecDeque<Vec<u8>> {
+        let mut lines = self.lines.lock().unwrap_or_else(PoisonError::into_inner);
+        std::mem::take(&mut *lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keeps_the_last_lines() {
+        let backfill = Backfill::new(2, LogLevel::Error);
+
+        assert_eq!(backfill.push(b"1".to_vec()), None);
+        assert_eq!(backfill.push(b"2".to_vec()), None);
+        assert_eq!(backfill.push(b"3".to_vec()), Some(b"1".to_vec()));
+
+        assert_eq!(backfill.take(), [b"2".to_vec(), b"3".to_vec()]);
+        assert!(backfill.take().is_empty());
+    }
+
+    #[test]
+    fn test_triggers_at_or_above_level() {
+        let backfill = Backfill::new(10, LogLevel::Warn);
+
+        assert!(!backfill.is_triggered_by(LogLevel::Info));
+        assert!(backfill.is_triggered_by(LogLevel::Warn));
+        assert!(backfill.is_triggered_by(LogLevel::Error));
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    io::Write,
+    mem,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    sanitize::Fnv1a,
+    sequence::{self, Sequence},
+};
+
+pub(crate) const REPEAT_COUNT_KEY: &str = "repeat_count";
+pub(crate) const FIRST_TIMESTAMP_KEY: &str = "first_timestamp";
+pub(crate) const LAST_TIMESTAMP_KEY: &str = "last_timestamp";
+
+/// Sent along with a line from a logger that collapses repeats
+pub(crate) struct Repeat {
+    /// Same logger and the same line apart from its timestamp
+    pub(crate) fingerprint: u64,
+    /// Where the line's timestamp is, quotes included
+    pub(crate) timestamp: Range<usize>,
+    /// How long repeats are held before their summary is written anyway
+    pub(crate) timeout: Duration,
+}
+
+impl Repeat {
+    /// `logger` tells loggers apart, lines from different ones are never repeats of each other.
+    /// `None` if the line doesn't start with a timestamp, which encoded events always do
+    pub(crate) fn new(logger: usize, line: &[u8], timeout: Duration) -> Option<Self> {
+        let timestamp = timestamp_range(line)?;
+        let mut hasher = Fnv1a::default();
+        let _ = hasher.write_all(&logger.to_le_bytes());
+        let _ = hasher.write_all(line.get(..timestamp.start)?);
+        let _ = hasher.write_all(line.get(timestamp.end..)?);
+        Some(Repeat {
+            fingerprint: hasher.0,
+            timestamp,
+            timeout,
+        })
+    }
+}
+
+// The first value in the line, the timestamp is always the first field
+fn timestamp_range(line: &[u8]) -> Option<Range<usize>> {
+    let mut escaped = false;
+    let mut quotes = line.iter().enumerate().filter_map(|(i, byte)| {
+        let quote = *byte == b'"' && !escaped;
+        escaped = *byte == b'\\' && !escaped;
+        quote.then_some(i)
+    });
+    // Past the key's quotes
+    let start = quotes.nth(2)?;
+    let end = quotes.next()?;
+    Some(start..end + 1)
+}
+
+struct Held {
+    fingerprint: u64,
+    count: u64,
+    // From the line that started the run
+    first_timestamp: Vec<u8>,
+    // Where the timestamp is in `line`
+    last_timestamp: Range<usize>,
+    // The latest repeat, the summary is this line with the repeat fields added
+    line: Vec<u8>,
+    // Only taken once the summary is written, held repeats don't use up numbers
+    sequence: Option<Sequence>,
+    deadline: Option<Instant>,
+}
+
+/// Lives on the worker. Writes the first of a run of identical events, holds back the rest
+/// and writes one summary for them with `repeat_count` and first/last timestamps.
+#[derive(Default)]
+pub(crate) struct Collapser {
+    last_written: Option<u64>,
+    // The timestamp of the line `last_written` is for
+    written_timestamp: Vec<u8>,
+    held: Option<Held>,
+}
+
+impl Collapser {
+    /// Writes `line` to `batch`, unless it repeats the line written before it. Then it's held back
+    /// until the run ends. Anything held before a different line is written first
+    pub(crate) fn write(
+        &mut self,
+        line: &[u8],
+        repeat: Option<Repeat>,
+        sequence: Option<Sequence>,
+        batch: &mut Vec<u8>,
+    ) {
+        let Some(repeat) = repeat else {
+            self.end_run(batch);
+            let _ = sequence::write_line(batch, line, sequence.as_ref());
+            return;
+        };
+
+        if let Some(held) = &mut self.held
+            && held.fingerprint == repeat.fingerprint
+        {
+            held.count += 1;
+            held.last_timestamp = repeat.timestamp;
+            held.line.clear();
+            held.line.extend_from_slice(line);
+            held.sequence = sequence;
+            return;
+        }
+        if self.held.is_none() && self.last_written == Some(repeat.fingerprint) {
+            self.held = Some(Held {
+                fingerprint: repeat.fingerprint,
+                count: 1,
+                first_timestamp: mem::take(&mut self.written_timestamp),
+                last_timestamp: repeat.timestamp,
+                line: line.to_vec(),
+                sequence,
+                deadline: Instant::now().checked_add(repeat.timeout),
+            });
+            return;
+        }
+
+        self.release(batch);
+        self.last_written = Some(repeat.fingerprint);
+        self.written_timestamp.clear();
+        self.written_timestamp
+            .extend_from_slice(line.get(repeat.timestamp).unwrap_or_default());
+        let _ = sequence::write_line(batch, line, sequence.as_ref());
+    }
+
+    /// Something else is being written, so the next line can't be a repeat
+    pub(crate) fn end_run(&mut self, batch: &mut Vec<u8>) {
+        self.release(batch);
+        self.last_written = None;
+    }
+
+    /// When the held repeats have to be written by, `None` if nothing is held
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.held.as_ref().and_then(|held| held.deadline)
+    }
+
+    /// Writes the summary of whatever is held, `true` if there was anything
+    pub(crate) fn release(&mut self, batch: &mut Vec<u8>) -> bool {
+        let Some(held) = self.held.take() else {
+            return false;
+        };
+        // The first timestamp went with the summary, so the next repeat starts a new run
+        self.last_written = None;
+        let start = batch.len();
+        let _ = sequence::write_line(batch, &held.line, held.sequence.as_ref());
+        // A single repeat is written as it was, there's nothing to summarize
+        if held.count == 1 {
+            return true;
+        }
+
+        // Splice the fields in before the closing brace, matching `pretty` if the line was
+        let Some(end) = batch
+            .get(start..)
+            .and_then(|line| line.iter().rposition(|byte| *byte == b'}'))
+            .map(|end| start + end)
+        else {
+            return true;
+        };
+        let pretty = end > start && batch.get(end - 1) == Some(&b'\n');
+        let (separator, colon) = if pretty { (",\n  ", ": ") } else { (",", ":") };
+
+        let count = held.count.to_string();
+        let fields = [
+            (REPEAT_COUNT_KEY, count.as_bytes()),
+            (FIRST_TIMESTAMP_KEY, &held.first_timestamp),
+            (
+                LAST_TIMESTAMP_KEY,
+                held.line.get(held.last_timestamp).unwrap_or_default(),
+            ),
+        ];
+        let mut spliced = Vec::new();
+        for (key, value) in fields {
+            spliced.extend_from_slice(separator.as_bytes());
+            spliced.push(b'"');
+            spliced.extend_from_slice(key.as_bytes());
+            spliced.push(b'"');
+            spliced.extend_from_slice(colon.as_bytes());
+            spliced.extend_from_slice(value);
+        }
+        // Goes in before the newline that comes before the brace when it's pretty
+        let at = if pretty { end - 1 } else { end };
+        batch.splice(at..at, spliced);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequence::Sequencer;
+    use serde_json::{Value, json};
+
+    fn line(timestamp: &str, rest: &str) -> String {
+        format!("{{\"ts\":\"{timestamp}\",{rest}}}\n")
+    }
+
+    fn write(collapser: &mut Collapser, line: &str, batch: &mut Vec<u8>) {
+        let repeat = Repeat::new(1, line.as_bytes(), Duration::from_secs(1));
+        collapser.write(line.as_bytes(), repeat, None, batch);
+    }
+
+    fn lines(batch: &[u8]) -> Vec<Value> {
+        serde_json::Deserializer::from_slice(batch)
+            .into_iter::<Value>()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn test_fingerprint_ignores_only_the_timestamp() {
+        let fingerprint = |logger, line: &str| {
+            Repeat::new(logger, line.as_bytes(), Duration::ZERO)
+                .unwrap()
+                .fingerprint
+        };
+        let first = line("t1", r#""message":"hi""#);
+
+        assert_eq!(
+            fingerprint(1, &first),
+            fingerprint(1, &line("t2", r#""message":"hi""#))
+        );
+        assert_ne!(
+            fingerprint(1, &first),
+            fingerprint(1, &line("t1", r#""message":"ho""#))
+        );
+        assert_ne!(fingerprint(1, &first), fingerprint(2, &first));
+    }
+
+    #[test]
+    fn test_finds_timestamps_with_escaped_quotes() {
+        let line = "{\n  \"ts\": \"\\\"t\\\"\",\n  \"message\": \"hi\"\n}\n";
+        let repeat = Repeat::new(1, line.as_bytes(), Duration::ZERO).unwrap();
+
+        assert_eq!(line.get(repeat.timestamp).unwrap(), "\"\\\"t\\\"\"");
+        assert!(Repeat::new(1, b"{}\n", Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn test_collapses_a_run_into_one_summary() {
+        let mut collapser = Collapser::default();
+        let mut batch = Vec::new();
+        for timestamp in ["t1", "t2", "t3", "t4"] {
+            let line = line(timestamp, r#""message":"health check failed""#);
+            write(&mut collapser, &line, &mut batch);
+        }
+        write(&mut collapser, &line("t5", r#""message":"ok""#), &mut batch);
+
+        assert_eq!(
+            lines(&batch),
+            vec![
+                json!({"ts": "t1", "message": "health check failed"}),
+                json!({
+                    "ts": "t4",
+                    "message": "health check failed",
+                    "repeat_count": 3,
+                    "first_timestamp": "t1",
+                    "last_timestamp": "t4",
+                }),
+                json!({"ts": "t5", "message": "ok"}),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_starts_over_after_a_summary() {
+        let mut collapser = Collapser::default();
+        let mut batch = Vec::new();
+        for timestamp in ["t1", "t2", "t3"] {
+            write(&mut collapser, &line(timestamp, r#""a":1"#), &mut batch);
+        }
+        // Like the deadline passing
+        assert!(collapser.release(&mut batch));
+        for timestamp in ["t4", "t5", "t6"] {
+            write(&mut collapser, &line(timestamp, r#""a":1"#), &mut batch);
+        }
+        collapser.release(&mut batch);
+
+        assert_eq!(
+            lines(&batch),
+            vec![
+                json!({"ts": "t1", "a": 1}),
+                json!({
+                    "ts": "t3",
+                    "a": 1,
+                    "repeat_count": 2,
+                    "first_timestamp": "t1",
+                    "last_timestamp": "t3",
+                }),
+                json!({"ts": "t4", "a": 1}),
+                json!({
+                    "ts": "t6",
+                    "a": 1,
+                    "repeat_count": 2,
+                    "first_timestamp": "t4",
+                    "last_timestamp": "t6",
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_single_repeat_is_written_as_is() {
+        let mut collapser = Collapser::default();
+        let mut batch = Vec::new();
+        write(&mut collapser, &line("t1", r#""a":1"#), &mut batch);
+        write(&mut collapser, &line("t2", r#""a":1"#), &mut batch);
+        assert_eq!(batch, line("t1", r#""a":1"#).as_bytes());
+
+        assert!(collapser.release(&mut batch));
+        assert_eq!(
+            batch,
+            (line("t1", r#""a":1"#) + &line("t2", r#""a":1"#)).as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_lines_without_fingerprint_break_the_run() {
+        let mut collapser = Collapser::default();
+        let mut batch = Vec::new();
+        write(&mut collapser, &line("t", r#""a":1"#), &mut batch);
+        collapser.write(b"{}\n", None, None, &mut batch);
+        write(&mut collapser, &line("t", r#""a":1"#), &mut batch);
+
+        assert_eq!(lines(&batch).len(), 3);
+        assert_eq!(collapser.deadline(), None);
+    }
+
+    #[test]
+    fn test_matches_pretty_output() {
+        let mut collapser = Collapser::default();
+        let mut batch = Vec::new();
+        for timestamp in ["t1", "t2", "t3"] {
+            let line = format!("{{\n  \"ts\": \"{timestamp}\",\n  \"a\": 1\n}}\n");
+            write(&mut collapser, &line, &mut batch);
+        }
+        batch.clear();
+
+        collapser.release(&mut batch);
+        assert_eq!(
+            String::from_utf8(batch).unwrap(),
+            "{\n  \"ts\": \"t3\",\n  \"a\": 1,\n  \"repeat_count\": 2,\n  \"first_timestamp\": \"t1\",\n  \"last_timestamp\": \"t3\"\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_held_repeats_are_not_numbered() {
+        let sequencer = Sequencer::new("seq".into());
+        let mut collapser = Collapser::default();
+        let mut batch = Vec::new();
+        for timestamp in ["t1", "t2", "t3"] {
+            let line = line(timestamp, r#""message":"retrying","seq":0"#);
+            let sequence = sequencer.locate(line.as_bytes());
+            let repeat = Repeat::new(1, line.as_bytes(), Duration::from_secs(1));
+            collapser.write(line.as_bytes(), repeat, sequence, &mut batch);
+        }
+        let line = line("t4", r#""message":"done","seq":0"#);
+        collapser.write(
+            line.as_bytes(),
+            None,
+            sequencer.locate(line.as_bytes()),
+            &mut batch,
+        );
+
+        let seqs: Vec<Value> = lines(&batch)
+            .iter()
+            .map(|line| line["seq"].clone())
+            .collect();
+        assert_eq!(seqs, [json!(0), json!(1), json!(2)]);
+    }
+}
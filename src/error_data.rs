@@ -0,0 +1,190 @@
+use serde::{
+    Serialize, Serializer,
+    ser::{SerializeMap, SerializeSeq},
+};
+use std::error::Error;
+
+pub(crate) const DEFAULT_ERROR_KEY: &str = "error";
+
+/// Serializes any error, `Serialize` or not, as
+/// `{"type": ..., "message": ..., "sources": [...], "backtrace": ...}`.
+///
+/// `sources` are the messages of the `source()` chain, outermost first.
+/// `type` is only known when built from a concrete error type, and `backtrace`
+/// only with the `anyhow` feature since std doesn't expose one on stable.
+/// Both are `null` otherwise so the shape stays the same for every error.
+///
+/// Use it as `custom_data` or pass the error to `Logger::error_with()`.
+#[derive(Clone, Copy)]
+pub struct ErrorData<'a> {
+    type_name: Option<&'static str>,
+    error: &'a (dyn Error + 'static),
+    backtrace: Option<&'a std::backtrace::Backtrace>,
+}
+
+impl<'a> ErrorData<'a> {
+    pub fn new<E: Error + 'static>(error: &'a E) -> Self {
+        ErrorData {
+            type_name: Some(std::any::type_name::<E>()),
+            error,
+            backtrace: None,
+        }
+    }
+
+    /// The chain is walked like any other error. The backtrace is included if anyhow captured one,
+    /// which depends on `RUST_BACKTRACE`/`RUST_LIB_BACKTRACE`
+    #[cfg(feature = "anyhow")]
+    pub fn from_anyhow(error: &'a anyhow::Error) -> Self {
+        let backtrace = error.backtrace();
+        ErrorData {
+            type_name: None,
+            error: error.as_ref(),
+            backtrace: (backtrace.status() == std::backtrace::BacktraceStatus::Captured)
+                .then_some(backtrace),
+        }
+    }
+
+    /// Only the chain, eyre doesn't give access to the backtrace its handler captured
+    #[cfg(feature = "eyre")]
+    pub fn from_eyre(error: &'a eyre::Report) -> Self {
+        ErrorData {
+            type_name: None,
+            error: error.as_ref(),
+            backtrace: None,
+        }
+    }
+}
+
+impl<'a, E: Error + 'static> From<&'a E> for ErrorData<'a> {
+    fn from(error: &'a E) -> Self {
+        ErrorData::new(error)
+    }
+}
+
+impl<'a> From<&'a (dyn Error + 'static)> for ErrorData<'a> {
+    fn from(error: &'a (dyn Error + 'static)) -> Self {
+        ErrorData {
+            type_name: None,
+            error,
+            backtrace: None,
+        }
+    }
+}
+
+impl<'a> From<&'a (dyn Error + Send + Sync + 'static)> for ErrorData<'a> {
+    fn from(error: &'a (dyn Error + Send + Sync + 'static)) -> Self {
+        ErrorData {
+            type_name: None,
+            error,
+            backtrace: None,
+        }
+    }
+}
+
+// Streams each source's Display output instead of collecting them into Strings first
+struct Sources<'a>(&'a (dyn Error + 'static));
+
+impl Serialize for Sources<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(None)?;
+        let mut source = self.0.source();
+        while let Some(error) = source {
+            seq.serialize_element(&DisplayStr(error))?;
+            source = error.source();
+        }
+        seq.end()
+    }
+}
+
+struct DisplayStr<'a, T: ?Sized>(&'a T);
+
+impl<T: std::fmt::Display + ?Sized> Serialize for DisplayStr<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self.0)
+    }
+}
+
+impl Serialize for ErrorData<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(4))?;
+        map.serialize_entry("type", &self.type_name)?;
+        map.serialize_entry("message", &DisplayStr(self.error))?;
+        map.serialize_entry("sources", &Sources(self.error))?;
+        map.serialize_entry("backtrace", &self.backtrace.map(DisplayStr))?;
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fmt;
+
+    #[derive(Debug)]
+    struct ConfigError(std::io::Error);
+
+    impl fmt::Display for ConfigError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "failed to load config")
+        }
+    }
+
+    impl Error for ConfigError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn test_serializes_type_message_and_sources() {
+        let error = ConfigError(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "config.toml is missing",
+        ));
+
+        let value = serde_json::to_value(ErrorData::new(&error)).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "type": std::any::type_name::<ConfigError>(),
+                "message": "failed to load config",
+                "sources": ["config.toml is missing"],
+                "backtrace": null,
+            })
+        );
+    }
+
+    #[test]
+    fn test_dyn_error_has_no_type() {
+        let error: Box<dyn Error + Send + Sync> = "plain message".into();
+
+        let value = serde_json::to_value(ErrorData::from(error.as_ref())).unwrap();
+        assert_eq!(value["type"], json!(null));
+        assert_eq!(value["message"], "plain message");
+        assert_eq!(value["sources"], json!([]));
+    }
+
+    #[cfg(feature = "anyhow")]
+    #[test]
+    fn test_anyhow_chain() {
+        let error = anyhow::anyhow!("root cause").context("outer");
+
+        let value = serde_json::to_value(ErrorData::from_anyhow(&error)).unwrap();
+        assert_eq!(value["message"], "outer");
+        assert_eq!(value["sources"], json!(["root cause"]));
+    }
+
+    #[cfg(feature = "eyre")]
+    #[test]
+    fn test_eyre_chain() {
+        use eyre::WrapErr;
+        let error = Err::<(), _>(eyre::eyre!("root cause"))
+            .wrap_err("outer")
+            .unwrap_err();
+
+        let value = serde_json::to_value(ErrorData::from_eyre(&error)).unwrap();
+        assert_eq!(value["message"], "outer");
+        assert_eq!(value["sources"], json!(["root cause"]));
+    }
+}
@@ -0,0 +1,96 @@
+use serde::Serialize;
+use std::sync::OnceLock;
+
+use crate::Logger;
+
+static GLOBAL: OnceLock<Logger> = OnceLock::new();
+
+/// Sets the logger used by `sjl::info()` and friends. Can only be set once,
+/// if one is already set the logger you passed in is handed back.
+///
+/// The global logger is never dropped, so call `sjl::flush()` before exiting
+/// or anything still batched is lost.
+pub fn set_global(logger: Logger) -> Result<(), Logger> {
+    GLOBAL.set(logger)
+}
+
+/// The logger set with `set_global()`, if there is one.
+pub fn global() -> Option<&'static Logger> {
+    GLOBAL.get()
+}
+
+/// Logs to the global logger. Does nothing if `set_global()` hasn't been called yet.
+#[track_caller]
+pub fn info<CustomData: Serialize>(message: impl AsRef<str>, custom_data: CustomData) {
+    if let Some(logger) = global() {
+        logger.info(message, custom_data);
+    }
+}
+
+/// Logs to the global logger. Does nothing if `set_global()` hasn't been called yet.
+#[track_caller]
+pub fn warn<CustomData: Serialize>(message: impl AsRef<str>, custom_data: CustomData) {
+    if let Some(logger) = global() {
+        logger.warn(message, custom_data);
+    }
+}
+
+/// Logs to the global logger. Does nothing if `set_global()` hasn't been called yet.
+#[track_caller]
+pub fn error<CustomData: Serialize>(message: impl AsRef<str>, custom_data: CustomData) {
+    if let Some(logger) = global() {
+        logger.error(message, custom_data);
+    }
+}
+
+/// Logs to the global logger. Does nothing if `set_global()` hasn't been called yet.
+#[track_caller]
+pub fn debug<CustomData: Serialize>(message: impl AsRef<str>, custom_data: CustomData) {
+    if let Some(logger) = global() {
+        logger.debug(message, custom_data);
+    }
+}
+
+/// Flushes the global logger, see `Logger::flush()`.
+pub fn flush() {
+    if let Some(logger) = global() {
+        logger.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LoggerOptions;
+
+    // The global can only be set once per process so everything lives in one test
+    #[test]
+    fn test_set_global_once() {
+        // Logging before a global is set is a no-op rather than a panic
+        info("dropped", ());
+
+        let logger = LoggerOptions::default().sequence(true).init();
+        assert!(set_global(logger.clone()).is_ok());
+        assert!(set_global(Logger::new()).is_err());
+
+        info("one", ());
+        warn("two", ());
+        flush();
+
+        // Clones share the sequence counter, so the global logged through our handle
+        assert!(std::ptr::eq(
+            global().unwrap().inner.as_ref(),
+            logger.inner.as_ref()
+        ));
+        assert_eq!(
+            logger
+                .inner
+                .sequence
+                .as_ref()
+                .unwrap()
+                .next
+                .load(std::sync::atomic::Ordering::Relaxed),
+            2
+        );
+    }
+}
@@ -0,0 +1,39 @@
+use std::{
+    fmt::Write,
+    hash::{BuildHasher, RandomState},
+};
+
+/// Random enough for correlating logs, not for anything security related.
+/// Each `RandomState` is seeded from the OS once per thread and then bumped on every call,
+/// so this never hands out the same value twice in a process.
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().hash_one(std::process::id())
+}
+
+/// 32 lowercase hex characters, the same shape as a W3C trace id
+pub(crate) fn random_id() -> String {
+    let mut id = String::with_capacity(32);
+    let _ = write!(id, "{:016x}{:016x}", random_u64(), random_u64());
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_id_shape() {
+        let id = random_id();
+        assert_eq!(id.len(), 32);
+        assert!(
+            id.chars()
+                .all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase())
+        );
+    }
+
+    #[test]
+    fn test_random_ids_are_unique() {
+        let ids: std::collections::HashSet<String> = (0..1000).map(|_| random_id()).collect();
+        assert_eq!(ids.len(), 1000);
+    }
+}
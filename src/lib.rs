@@ -1,11 +1,52 @@
 pub use serde_json::json;
 
+// Lets the derive's `::sjl::...` paths resolve in this crate's own tests
+#[cfg(test)]
+extern crate self as sjl;
+
 mod logger;
 pub use logger::Logger;
 
+mod backfill;
+mod collapse;
+mod error_data;
+pub use error_data::ErrorData;
+
+mod global;
+pub use global::{debug, error, flush, global, info, set_global, warn};
+
+mod ids;
 mod log_event;
 mod log_level;
+mod metadata;
+#[cfg(feature = "opentelemetry")]
+mod otel;
+mod rate_limit;
+pub use rate_limit::RateLimitKey;
+mod sampling;
+mod sanitize;
+#[cfg(feature = "scrub")]
+mod scrub;
+mod sequence;
+mod span;
+mod timed;
+pub use timed::Timer;
 mod timestamp;
+pub mod trace;
 pub use log_level::LogLevel;
+pub use sanitize::RedactionStyle;
+#[cfg(feature = "scrub")]
+pub use scrub::Scrubber;
+pub use timestamp::Timezone;
+mod loggable;
+#[doc(hidden)]
+pub use loggable::__private;
+pub use loggable::{ForLog, Loggable};
+#[cfg(feature = "derive")]
+pub use sjl_derive::Loggable;
 mod logger_options;
 pub use logger_options::LoggerOptions;
+mod panic_hook;
+pub use panic_hook::install_panic_hook;
+mod writer;
+pub use writer::Writer;
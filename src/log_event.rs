@@ -1,32 +1,152 @@
+use std::{cell::Cell, panic::Location};
+
 use serde::{Serialize, ser::SerializeMap};
 use serde_json::{Map, Value};
 
-use crate::timestamp::FormattedTimestamp;
+use crate::{
+    backfill::BACKFILL_KEY,
+    error_data::ErrorData,
+    sampling::SAMPLE_RATE_KEY,
+    sanitize::{Marker, Sanitized},
+    span::{PARENT_SPAN_ID_KEY, SPAN_ID_KEY, SPAN_KEYS, Span, TRACE_FLAGS_KEY, TRACE_ID_KEY},
+    timestamp::FormattedTimestamp,
+};
 
 pub(crate) struct LogEvent<'a, Data: Serialize> {
-    pub(crate) timestamp: FormattedTimestamp,
+    pub(crate) timestamp: FormattedTimestamp<'a>,
     pub(crate) timestamp_key: &'a str,
     pub(crate) level: &'a str,
     pub(crate) message: &'a str,
+    // (key, value) pairs captured per event, `None` when turned off
+    pub(crate) sequence: Option<(&'a str, u64)>,
+    pub(crate) thread_name: Option<(&'a str, Option<&'a str>)>,
+    pub(crate) thread_id: Option<(&'a str, u64)>,
+    pub(crate) caller: Option<&'static Location<'static>>,
+    pub(crate) sample_rate: Option<f64>,
+    // Held back in the fingers crossed buffer and written because of a later event
+    pub(crate) backfill: bool,
+    pub(crate) span: Option<&'a Span>,
+    pub(crate) error: Option<EventData<'a, ErrorData<'a>>>,
+    pub(crate) error_key: &'a str,
     pub(crate) context: &'a Map<String, Value>,
     pub(crate) data: Option<&'a Data>,
+    // Set while serializing if a size limit cut something off
+    pub(crate) truncated: &'a Cell<bool>,
+}
+
+// What goes under `data` or the error key, swapped for a marker when the line is over `max_line_bytes`
+pub(crate) enum EventData<'a, T> {
+    Value(Sanitized<'a, T>),
+    Truncated(Marker),
+}
+
+impl<T: Serialize> Serialize for EventData<'_, T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            EventData::Value(value) => value.serialize(serializer),
+            EventData::Truncated(marker) => marker.serialize(serializer),
+        }
+    }
+}
+
+pub(crate) const CALLER_KEY: &str = "caller";
+pub(crate) const TRUNCATED_KEY: &str = "truncated";
+
+// `{"file": "src/main.rs", "line": 12}`
+struct Caller(&'static Location<'static>);
+
+impl Serialize for Caller {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("file", self.0.file())?;
+        map.serialize_entry("line", &self.0.line())?;
+        map.end()
+    }
+}
+
+impl<'a, Data: Serialize> LogEvent<'a, Data> {
+    /// Keeps only the timestamp, level, message and sequence, for when the rest doesn't fit in a line
+    pub(crate) fn strip(&mut self, no_context: &'a Map<String, Value>) {
+        self.thread_name = None;
+        self.thread_id = None;
+        self.caller = None;
+        self.sample_rate = None;
+        self.span = None;
+        self.error = None;
+        self.context = no_context;
+        self.data = None;
+    }
 }
 
-impl<'a, Data: Serialize> Serialize for LogEvent<'a, Data> {
+impl<Data: Serialize> Serialize for LogEvent<'_, Data> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        // Determine the size first
-        let len = 3 + self.context.len() + self.data.is_some() as usize;
+        // Determine the size first. `truncated` isn't known until the end, serde_json doesn't mind
+        let len = 3
+            + usize::from(self.sequence.is_some())
+            + usize::from(self.thread_name.is_some())
+            + usize::from(self.thread_id.is_some())
+            + usize::from(self.caller.is_some())
+            + usize::from(self.sample_rate.is_some())
+            + usize::from(self.backfill)
+            + usize::from(self.error.is_some())
+            + self.span.map_or(0, |span| {
+                2 + usize::from(span.parent_id.is_some()) + usize::from(span.flags.is_some())
+            })
+            + self.context.len()
+            + usize::from(self.data.is_some());
         let mut map = serializer.serialize_map(Some(len))?;
 
         map.serialize_entry(self.timestamp_key, &self.timestamp)?;
         map.serialize_entry("level", self.level)?;
         map.serialize_entry("message", self.message)?;
 
-        // Flatten context keys
+        if let Some((key, sequence)) = self.sequence {
+            map.serialize_entry(key, &sequence)?;
+        }
+        if let Some((key, name)) = self.thread_name {
+            map.serialize_entry(key, &name)?;
+        }
+        if let Some((key, id)) = self.thread_id {
+            map.serialize_entry(key, &id)?;
+        }
+        if let Some(caller) = self.caller {
+            map.serialize_entry(CALLER_KEY, &Caller(caller))?;
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            map.serialize_entry(SAMPLE_RATE_KEY, &sample_rate)?;
+        }
+        if self.backfill {
+            map.serialize_entry(BACKFILL_KEY, &true)?;
+        }
+        if let Some(error) = &self.error {
+            map.serialize_entry(self.error_key, error)?;
+        }
+
+        if let Some(span) = self.span {
+            map.serialize_entry(TRACE_ID_KEY, &span.trace_id)?;
+            map.serialize_entry(SPAN_ID_KEY, &span.id)?;
+            if let Some(parent_span_id) = &span.parent_id {
+                map.serialize_entry(PARENT_SPAN_ID_KEY, parent_span_id)?;
+            }
+            if let Some(flags) = &span.flags {
+                map.serialize_entry(TRACE_FLAGS_KEY, flags)?;
+            }
+        }
+
+        // Flatten context keys, the span's ids and the error win over context keys with the same name
         for (k, v) in self.context {
+            if self.span.is_some() && SPAN_KEYS.contains(&k.as_str()) {
+                continue;
+            }
+            if self.error.is_some() && k == self.error_key {
+                continue;
+            }
             map.serialize_entry(k, v)?;
         }
 
@@ -35,6 +155,11 @@ impl<'a, Data: Serialize> Serialize for LogEvent<'a, Data> {
             map.serialize_entry("data", data)?;
         }
 
+        // Last so it covers everything above
+        if self.truncated.get() {
+            map.serialize_entry(TRUNCATED_KEY, &true)?;
+        }
+
         map.end()
     }
 }
@@ -43,6 +168,7 @@ impl<'a, Data: Serialize> Serialize for LogEvent<'a, Data> {
 mod tests {
     use serde::{Deserialize, Serialize};
     use serde_json::{Map, Value, json};
+    use std::cell::Cell;
     #[derive(Serialize, Deserialize)]
     enum UserType {
         Basic,
@@ -56,9 +182,10 @@ mod tests {
     }
 
     use crate::{
-        LogLevel,
-        log_event::LogEvent,
-        timestamp::{DEFAULT_TS_FORMAT, FormattedTimestamp},
+        ErrorData, LogLevel,
+        log_event::{EventData, LogEvent},
+        sanitize::Sanitized,
+        timestamp::{DEFAULT_TS_FORMAT, FormattedTimestamp, TimestampFormat, Timezone},
     };
 
     #[test]
@@ -73,13 +200,24 @@ mod tests {
         test_map.insert("test_map".to_string(), Value::String("test_value".into()));
         test_map.insert("user".to_string(), serde_json::to_value(user).unwrap());
 
-        let ts = FormattedTimestamp::new(Some(DEFAULT_TS_FORMAT));
+        let format = TimestampFormat::parse(Some(DEFAULT_TS_FORMAT), Timezone::Utc);
+        let ts = FormattedTimestamp::new(&format, Timezone::Utc);
         let event = LogEvent {
             level: LogLevel::Info.as_str(),
             message: "Saul Goodman",
             timestamp: ts,
             timestamp_key: "poop",
+            sequence: None,
+            thread_name: None,
+            thread_id: None,
+            caller: None,
+            sample_rate: None,
+            backfill: false,
+            span: None,
+            error: None,
+            error_key: "error",
             data: Some(&json!({"sample_key": "sample_data"})),
+            truncated: &Cell::new(false),
             context: &test_map,
         };
 
@@ -103,17 +241,216 @@ mod tests {
 
     #[test]
     fn test_omits_custom_data_when_none() {
+        let format = TimestampFormat::parse(None, Timezone::Utc);
         let event: LogEvent<()> = LogEvent {
             level: LogLevel::Info.as_str(),
             message: "Saul Goodman",
-            timestamp: FormattedTimestamp::new(None),
+            timestamp: FormattedTimestamp::new(&format, Timezone::Utc),
             data: None,
+            truncated: &Cell::new(false),
             context: &Map::new(),
             timestamp_key: "timestamp",
+            sequence: None,
+            thread_name: None,
+            thread_id: None,
+            caller: None,
+            sample_rate: None,
+            backfill: false,
+            span: None,
+            error: None,
+            error_key: "error",
         };
 
         let result = serde_json::to_string(&event).unwrap();
         let parsed_result: serde_json::Value = serde_json::from_str(&result).unwrap();
         assert!(parsed_result.get("data").is_none())
     }
+
+    #[test]
+    fn test_serializes_thread_fields() {
+        let format = TimestampFormat::parse(None, Timezone::Utc);
+        let event: LogEvent<()> = LogEvent {
+            level: LogLevel::Info.as_str(),
+            message: "Saul Goodman",
+            timestamp: FormattedTimestamp::new(&format, Timezone::Utc),
+            data: None,
+            truncated: &Cell::new(false),
+            context: &Map::new(),
+            timestamp_key: "timestamp",
+            sequence: None,
+            thread_name: Some(("thread", Some("worker-1"))),
+            thread_id: Some(("tid", 7)),
+            caller: None,
+            sample_rate: None,
+            backfill: false,
+            span: None,
+            error: None,
+            error_key: "error",
+        };
+
+        let parsed_result = serde_json::to_value(&event).unwrap();
+        assert_eq!(parsed_result["thread"], "worker-1");
+        assert_eq!(parsed_result["tid"], 7);
+    }
+
+    #[test]
+    fn test_unnamed_thread_is_null() {
+        let format = TimestampFormat::parse(None, Timezone::Utc);
+        let event: LogEvent<()> = LogEvent {
+            level: LogLevel::Info.as_str(),
+            message: "Saul Goodman",
+            timestamp: FormattedTimestamp::new(&format, Timezone::Utc),
+            data: None,
+            truncated: &Cell::new(false),
+            context: &Map::new(),
+            timestamp_key: "timestamp",
+            sequence: None,
+            thread_name: Some(("thread", None)),
+            thread_id: None,
+            caller: None,
+            sample_rate: None,
+            backfill: false,
+            span: None,
+            error: None,
+            error_key: "error",
+        };
+
+        let parsed_result = serde_json::to_value(&event).unwrap();
+        assert!(parsed_result["thread"].is_null());
+        assert!(parsed_result.get("thread_id").is_none());
+    }
+
+    #[test]
+    fn test_serializes_sequence_after_message() {
+        let format = TimestampFormat::parse(None, Timezone::Utc);
+        let event: LogEvent<()> = LogEvent {
+            level: LogLevel::Info.as_str(),
+            message: "Saul Goodman",
+            timestamp: FormattedTimestamp::new(&format, Timezone::Utc),
+            data: None,
+            truncated: &Cell::new(false),
+            context: &Map::new(),
+            timestamp_key: "timestamp",
+            sequence: Some(("seq", 42)),
+            thread_name: None,
+            thread_id: None,
+            caller: None,
+            sample_rate: None,
+            backfill: false,
+            span: None,
+            error: None,
+            error_key: "error",
+        };
+
+        let result = serde_json::to_string(&event).unwrap();
+        assert!(result.contains("\"message\":\"Saul Goodman\",\"seq\":42"));
+    }
+
+    #[test]
+    fn test_serializes_caller() {
+        let format = TimestampFormat::parse(None, Timezone::Utc);
+        let location = std::panic::Location::caller();
+        let event: LogEvent<()> = LogEvent {
+            level: LogLevel::Info.as_str(),
+            message: "Saul Goodman",
+            timestamp: FormattedTimestamp::new(&format, Timezone::Utc),
+            data: None,
+            truncated: &Cell::new(false),
+            context: &Map::new(),
+            timestamp_key: "timestamp",
+            sequence: None,
+            thread_name: None,
+            thread_id: None,
+            caller: Some(location),
+            sample_rate: None,
+            backfill: false,
+            span: None,
+            error: None,
+            error_key: "error",
+        };
+
+        let parsed_result = serde_json::to_value(&event).unwrap();
+        assert_eq!(parsed_result["caller"]["file"], file!());
+        assert_eq!(parsed_result["caller"]["line"], location.line());
+    }
+
+    #[test]
+    fn test_serializes_error_before_context() {
+        let format = TimestampFormat::parse(None, Timezone::Utc);
+        let io_error = std::io::Error::other("disk full");
+        let error = ErrorData::new(&io_error);
+        let truncated = Cell::new(false);
+        let mut context = Map::new();
+        context.insert("service".to_string(), json!("payments"));
+        let event: LogEvent<()> = LogEvent {
+            level: LogLevel::Error.as_str(),
+            message: "Saul Goodman",
+            timestamp: FormattedTimestamp::new(&format, Timezone::Utc),
+            data: None,
+            truncated: &Cell::new(false),
+            context: &context,
+            timestamp_key: "timestamp",
+            sequence: None,
+            thread_name: None,
+            thread_id: None,
+            caller: None,
+            sample_rate: None,
+            backfill: false,
+            span: None,
+            error: Some(EventData::Value(Sanitized::new(&error, None, &truncated))),
+            error_key: "error",
+        };
+
+        let serialized = serde_json::to_string(&event).unwrap();
+        let parsed_result: Value = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(parsed_result["error"]["message"], "disk full");
+        assert!(serialized.find("\"error\"") < serialized.find("\"service\""));
+    }
+
+    #[test]
+    fn test_error_replaces_context_key_with_the_same_name() {
+        let format = TimestampFormat::parse(None, Timezone::Utc);
+        let io_error = std::io::Error::other("disk full");
+        let error = ErrorData::new(&io_error);
+        let mut context = Map::new();
+        context.insert("error".to_string(), json!("from context"));
+        let mut event: LogEvent<()> = LogEvent {
+            level: LogLevel::Error.as_str(),
+            message: "Saul Goodman",
+            timestamp: FormattedTimestamp::new(&format, Timezone::Utc),
+            data: None,
+            truncated: &Cell::new(false),
+            context: &context,
+            timestamp_key: "timestamp",
+            sequence: None,
+            thread_name: None,
+            thread_id: None,
+            caller: None,
+            sample_rate: None,
+            backfill: false,
+            span: None,
+            error: None,
+            error_key: "error",
+        };
+
+        let serialized = serde_json::to_string(&event).unwrap();
+        assert_eq!(serialized.matches("\"error\":").count(), 1);
+        assert!(serialized.contains("\"error\":\"from context\""));
+
+        let error_truncated = Cell::new(false);
+        event.error = Some(EventData::Value(Sanitized::new(
+            &error,
+            None,
+            &error_truncated,
+        )));
+        let serialized = serde_json::to_string(&event).unwrap();
+        assert_eq!(serialized.matches("\"error\":").count(), 1);
+        let parsed_result: Value = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(parsed_result["error"]["message"], "disk full");
+
+        event.error_key = "err";
+        let parsed_result = serde_json::to_value(&event).unwrap();
+        assert_eq!(parsed_result["err"]["message"], "disk full");
+        assert_eq!(parsed_result["error"], "from context");
+    }
 }
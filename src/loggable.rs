@@ -0,0 +1,149 @@
+use serde::{Serialize, Serializer};
+
+/// A log specific way to serialize a type, separate from its `Serialize` impl.
+///
+/// Usually derived with `#[derive(Loggable)]` (`derive` feature) so a domain type can be logged
+/// with sensitive fields masked, without a parallel struct just for logging:
+///
+/// ```ignore
+/// #[derive(Serialize, Loggable)]
+/// struct User {
+///     name: String,
+///     #[sjl(redact)]
+///     password: String,
+///     #[sjl(hash)]
+///     email: String,
+///     #[sjl(skip)]
+///     avatar: Vec<u8>,
+///     #[sjl(truncate = 64, rename = "about")]
+///     bio: String,
+/// }
+///
+/// logger.info("User created", user.for_log());
+/// ```
+pub trait Loggable {
+    fn serialize_for_log<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>;
+
+    /// Wraps `self` so it's serialized with `serialize_for_log` when passed as `custom_data`
+    fn for_log(&self) -> ForLog<'_, Self> {
+        ForLog(self)
+    }
+}
+
+/// See `Loggable::for_log()`
+pub struct ForLog<'a, T: ?Sized>(&'a T);
+
+impl<T: Loggable + ?Sized> Serialize for ForLog<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize_for_log(serializer)
+    }
+}
+
+/// Used by the code `#[derive(Loggable)]` generates, not part of the public API
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::sanitize::{Hashed, REDACTED};
+    pub use serde::{Serialize, Serializer, ser::SerializeStruct};
+
+    /// The first `max_chars` characters, followed by `…` if anything was cut off
+    pub struct Truncated<'a> {
+        pub value: &'a str,
+        pub max_chars: usize,
+    }
+
+    impl Serialize for Truncated<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self.value.char_indices().nth(self.max_chars) {
+                Some((end, _)) => serializer.collect_str(&format_args!(
+                    "{}…",
+                    self.value.get(..end).unwrap_or_default()
+                )),
+                None => serializer.serialize_str(self.value),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sjl_derive::Loggable;
+
+    #[derive(Serialize, Loggable)]
+    struct User {
+        name: String,
+        #[sjl(redact)]
+        password: String,
+        #[sjl(hash)]
+        email: String,
+        #[sjl(skip)]
+        #[allow(dead_code)]
+        avatar: Vec<u8>,
+        #[sjl(truncate = 5, rename = "about")]
+        bio: String,
+    }
+
+    #[derive(Loggable)]
+    struct Wrapper<T> {
+        #[sjl(rename = "inner")]
+        value: T,
+    }
+
+    fn user() -> User {
+        User {
+            name: "saul".to_string(),
+            password: "hunter2".to_string(),
+            email: "saul@goodman.law".to_string(),
+            avatar: vec![1, 2, 3],
+            bio: "Better call me".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_derive_applies_field_attributes() {
+        let value = serde_json::to_value(user().for_log()).unwrap();
+
+        let email = value["email"].as_str().unwrap();
+        assert!(email.starts_with("hash:"));
+        assert_eq!(
+            value,
+            json!({
+                "name": "saul",
+                "password": "[REDACTED]",
+                "email": email,
+                "about": "Bette…",
+            })
+        );
+    }
+
+    #[test]
+    fn test_serialize_impl_is_untouched() {
+        let value = serde_json::to_value(user()).unwrap();
+
+        assert_eq!(value["password"], "hunter2");
+        assert_eq!(value["avatar"], json!([1, 2, 3]));
+    }
+
+    #[test]
+    fn test_hash_is_stable() {
+        let first = serde_json::to_value(user().for_log()).unwrap();
+        let second = serde_json::to_value(user().for_log()).unwrap();
+        assert_eq!(first["email"], second["email"]);
+    }
+
+    #[test]
+    fn test_generic_struct() {
+        let value = serde_json::to_value(Wrapper { value: 42 }.for_log()).unwrap();
+        assert_eq!(value, json!({"inner": 42}));
+    }
+
+    #[test]
+    fn test_truncate_leaves_short_strings_alone() {
+        let truncated = __private::Truncated {
+            value: "héllo",
+            max_chars: 5,
+        };
+        assert_eq!(serde_json::to_value(truncated).unwrap(), "héllo");
+    }
+}
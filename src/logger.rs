@@ -1,53 +1,79 @@
 use crate::{
-    log_event::LogEvent, log_level::LogLevel, logger_options::LoggerOptions,
-    timestamp::FormattedTimestamp,
+    backfill::Backfill,
+    collapse::Repeat,
+    error_data::ErrorData,
+    log_event::{EventData, LogEvent},
+    log_level::LogLevel,
+    logger_options::LoggerOptions,
+    metadata,
+    rate_limit::{Key, RateLimiter, Summary, Verdict},
+    sampling::{Decision, Sampler},
+    sanitize::{self, Marker, Sanitized, Sanitizer},
+    sequence::{self, Sequencer},
+    span::Span,
+    timed::Timer,
+    timestamp::{FormattedTimestamp, TimestampFormat, Timezone},
+    trace::TraceContext,
+    writer::Writer,
 };
-use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
-use crossbeam_queue::ArrayQueue;
 use serde::Serialize;
 use serde_json::{Map, Value};
 use std::{
-    io::Write,
+    borrow::Cow,
+    cell::Cell,
+    panic::Location,
     sync::Arc,
     time::{Duration, Instant},
 };
 
-const OVERSIZED_LOG_PREVIEW_LENGTH: usize = 200; // todo allow override?
-const OVERSIZED_LOG_RESET_WINDOW: Duration = Duration::from_secs(4 * 60 * 60); // todo allow override?
-
+/// Cloning is cheap, clones share the same writer, context and sequence counter.
 #[must_use = "Logger does nothing unless you keep it and call log methods like `.info()`"]
+#[derive(Clone)]
 pub struct Logger {
-    pub(crate) sender: Option<Sender<Vec<u8>>>,
-    pub(crate) worker: Option<std::thread::JoinHandle<()>>,
-    pub(crate) buffer_pool: Arc<ArrayQueue<Vec<u8>>>,
-    pub(crate) buffer_pool_initial_capacity: usize,
+    pub(crate) inner: Arc<LoggerInner>,
+    // Set on child loggers from `span()`
+    pub(crate) span: Option<Arc<Span>>,
+}
+
+// Mirrors the boolean options from `LoggerOptions`
+#[allow(clippy::struct_excessive_bools)]
+pub(crate) struct LoggerInner {
+    pub(crate) writer: Writer,
 
     // Options
     pub(crate) min_level: LogLevel,
-    pub(crate) timestamp_format: Option<&'static str>,
-    pub(crate) timestamp_key: &'static str,
+    pub(crate) flush_on_level: Option<LogLevel>,
+    pub(crate) flush_on_level_blocking: bool,
+    pub(crate) timestamp_format: TimestampFormat,
+    pub(crate) timestamp_key: Cow<'static, str>,
+    pub(crate) error_key: Cow<'static, str>,
+    pub(crate) timezone: Timezone,
+    pub(crate) sequence: Option<Sequencer>,
+    pub(crate) caller_location: bool,
+    pub(crate) thread_name_key: Option<Cow<'static, str>>,
+    pub(crate) thread_id_key: Option<Cow<'static, str>>,
     pub(crate) context: Map<String, Value>,
     pub(crate) pretty: bool,
+    pub(crate) sanitizer: Option<Sanitizer>,
+    pub(crate) max_line_bytes: Option<usize>,
+    // Every line carries the context, so every line is marked if it was cut at `init()`
+    pub(crate) context_truncated: bool,
+    pub(crate) sampler: Option<Sampler>,
+    pub(crate) rate_limiter: Option<RateLimiter>,
+    pub(crate) collapse_repeats: Option<Duration>,
+    pub(crate) backfill: Option<Backfill>,
+    pub(crate) span_events: Option<LogLevel>,
 }
 
+// `,\n  "truncated": true` with `pretty`, a bit more than the compact form
+const TRUNCATED_FLAG_LEN: usize = 21;
+
 impl Default for Logger {
     fn default() -> Self {
         LoggerOptions::default().init()
     }
 }
 
-impl Drop for Logger {
-    fn drop(&mut self) {
-        // Drop the sender so worker gets Disconnected
-        self.sender.take();
-
-        // Wait for thread to flush and exit
-        if let Some(worker) = self.worker.take() {
-            let _ = worker.join();
-        }
-    }
-}
-
 impl Logger {
     pub fn builder() -> LoggerOptions {
         LoggerOptions::default()
@@ -55,249 +81,550 @@ impl Logger {
     pub fn new() -> Self {
         LoggerOptions::default().init()
     }
+    #[track_caller]
     pub fn info<CustomData: Serialize>(&self, message: impl AsRef<str>, custom_data: CustomData) {
-        self.log(LogLevel::Info, message.as_ref(), custom_data);
+        self.log(LogLevel::Info, message.as_ref(), None, custom_data);
     }
+    #[track_caller]
     pub fn warn<CustomData: Serialize>(&self, message: impl AsRef<str>, custom_data: CustomData) {
-        self.log(LogLevel::Warn, message.as_ref(), custom_data);
+        self.log(LogLevel::Warn, message.as_ref(), None, custom_data);
     }
+    #[track_caller]
     pub fn error<CustomData: Serialize>(&self, message: impl AsRef<str>, custom_data: CustomData) {
-        self.log(LogLevel::Error, message.as_ref(), custom_data);
+        self.log(LogLevel::Error, message.as_ref(), None, custom_data);
     }
+    #[track_caller]
     pub fn debug<CustomData: Serialize>(&self, message: impl AsRef<str>, custom_data: CustomData) {
-        self.log(LogLevel::Debug, message.as_ref(), custom_data);
+        self.log(LogLevel::Debug, message.as_ref(), None, custom_data);
+    }
+
+    /// Logs at Error level with `error` under a top-level `error` field, see `ErrorData` for the shape.
+    /// Takes `&err` for any `std::error::Error` or `&dyn Error`.
+    /// For `anyhow`/`eyre` errors pass `ErrorData::from_anyhow(&err)` / `ErrorData::from_eyre(&err)`.
+    #[track_caller]
+    pub fn error_with<'e, CustomData: Serialize>(
+        &self,
+        message: impl AsRef<str>,
+        error: impl Into<ErrorData<'e>>,
+        custom_data: CustomData,
+    ) {
+        self.log(
+            LogLevel::Error,
+            message.as_ref(),
+            Some(error.into()),
+            custom_data,
+        );
+    }
+
+    /// Times an operation until the returned `Timer` is dropped, then logs `"{name} finished"`
+    /// with `duration_ms` and `outcome` next to the fields of `custom_data` under `data`.
+    /// `custom_data` has to serialize to a map (a struct, `json!({...})`) or be `()`.
+    ///
+    /// ```ignore
+    /// let _timer = logger.timed("db.query", json!({"table": "users"})).warn_after(Duration::from_millis(100));
+    /// ```
+    #[track_caller]
+    pub fn timed<CustomData: Serialize>(
+        &self,
+        name: impl Into<String>,
+        custom_data: CustomData,
+    ) -> Timer<CustomData> {
+        Timer::new(self.clone(), name.into(), Location::caller(), custom_data)
+    }
+
+    /// A child logger for one unit of work. Its events get `trace_id`, `span_id` and
+    /// `parent_span_id` next to the context: a new trace for spans of a plain logger
+    /// (or the active OpenTelemetry span's with the `opentelemetry` feature),
+    /// the parent's trace and span id for spans of a span's logger.
+    ///
+    /// With `LoggerOptions::span_events()` it also logs `"{name} entered"` now and
+    /// `"{name} exited"` with `duration_ms` once the child logger and all its clones are dropped.
+    #[track_caller]
+    pub fn span(&self, name: impl Into<String>) -> Logger {
+        let parent = self.span.as_deref();
+        // Spans of a plain logger continue the active OpenTelemetry span's trace if there is one
+        #[cfg(feature = "opentelemetry")]
+        let active_span = parent.is_none().then(crate::otel::active_span).flatten();
+        #[cfg(feature = "opentelemetry")]
+        let parent = parent.or(active_span.as_ref());
+
+        let span = Span::new(name.into(), parent);
+        let span = match self.inner.span_events {
+            Some(level) => Span::enter(self, span, level, Location::caller()),
+            None => Arc::new(span),
+        };
+        Logger {
+            inner: Arc::clone(&self.inner),
+            span: Some(span),
+        }
+    }
+
+    /// A child logger that continues the trace from an incoming `traceparent`. Its events get the caller's
+    /// `trace_id` and `trace_flags`, a `span_id` of their own and the caller's span as `parent_span_id`.
+    pub fn with_trace_context(&self, trace_context: &TraceContext) -> Logger {
+        Logger {
+            inner: Arc::clone(&self.inner),
+            span: Some(Arc::new(Span::remote(trace_context))),
+        }
+    }
+
+    /// The trace context to send with outgoing requests, `traceparent()` and `tracestate()` give the headers.
+    /// `None` unless this logger came from `span()` or `with_trace_context()`
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        self.span.as_deref().map(Span::trace_context)
+    }
+
+    /// Writes out everything that's been logged so far and waits until it's done.
+    /// Useful before `std::process::exit`, which skips `Drop` and would lose whatever is still batched.
+    /// Does nothing in synchronous mode since every log is written right away.
+    pub fn flush(&self) {
+        self.inner.writer.flush();
+    }
+
+    /// How many strings (the message or values in `data`/`context`) scrubbers have changed so far.
+    /// Context is scrubbed once at `init()` so it only counts once
+    #[cfg(feature = "scrub")]
+    pub fn scrubbed_count(&self) -> u64 {
+        self.inner
+            .sanitizer
+            .as_ref()
+            .and_then(|sanitizer| sanitizer.scrubbers.as_ref())
+            .map_or(0, crate::scrub::Scrubbers::scrubbed_count)
+    }
+
+    /// The writer this logger sends its logs through. Pass it to `LoggerOptions::writer()`
+    /// so other loggers share the same background thread and output stream.
+    pub fn writer(&self) -> Writer {
+        self.inner.writer.clone()
     }
 
     fn should_log(&self, log_level: LogLevel) -> bool {
-        log_level.severity() >= self.min_level.severity()
+        log_level.severity() >= self.inner.min_level.severity()
     }
+
+    // `#[track_caller]` all the way down so `Location::caller()` points at the user's code
+    #[track_caller]
     fn log<CustomData: Serialize>(
         &self,
         log_level: LogLevel,
         message: impl AsRef<str>,
+        error: Option<ErrorData<'_>>,
+        custom_data: CustomData,
+    ) {
+        self.log_from(
+            Some(Location::caller()),
+            log_level,
+            message.as_ref(),
+            error,
+            custom_data,
+        );
+    }
+
+    // For events logged somewhere other than where they came from, like a `Timer` being dropped.
+    // `None` leaves `caller` out when the real one isn't known, like for a panic
+    pub(crate) fn log_from<CustomData: Serialize>(
+        &self,
+        caller: Option<&'static Location<'static>>,
+        log_level: LogLevel,
+        message: &str,
+        error: Option<ErrorData<'_>>,
+        custom_data: CustomData,
+    ) {
+        self.log_in(
+            self.span.as_deref(),
+            caller,
+            log_level,
+            message,
+            error,
+            custom_data,
+        );
+    }
+
+    // A span's own enter and exit events go through here since the span isn't on a logger yet, or anymore
+    pub(crate) fn log_in<CustomData: Serialize>(
+        &self,
+        span: Option<&Span>,
+        caller: Option<&'static Location<'static>>,
+        log_level: LogLevel,
+        message: &str,
+        error: Option<ErrorData<'_>>,
         custom_data: CustomData,
     ) {
-        if !self.should_log(log_level) {
+        let inner = &*self.inner;
+        let should_log = self.should_log(log_level);
+        if !should_log && inner.backfill.is_none() {
             return;
         }
 
-        if self.sender.is_none() {
+        // Events outside an sjl span pick up the active OpenTelemetry span instead
+        #[cfg(feature = "opentelemetry")]
+        let active_span = span.is_none().then(crate::otel::active_span).flatten();
+        #[cfg(feature = "opentelemetry")]
+        let span = span.or(active_span.as_ref());
+
+        if !should_log {
+            if let Some(backfill) = &inner.backfill {
+                let extras = Extras {
+                    caller,
+                    sample_rate: None,
+                    backfill: true,
+                    span,
+                    error,
+                };
+                inner.hold_back(backfill, log_level, message, extras, custom_data);
+            }
             return;
         }
+        // What led up to this goes out first
+        if let Some(backfill) = &inner.backfill
+            && backfill.is_triggered_by(log_level)
+        {
+            for line in backfill.take() {
+                inner.writer.write(line, None, None);
+            }
+        }
 
-        // Don't serialize the empty data: () in the log event to null, just skip it
-        let data = if size_of::<CustomData>() == 0 {
-            None
-        } else {
-            Some(&custom_data)
+        let sample_rate = match inner
+            .sampler
+            .as_ref()
+            .map_or(Decision::Keep, |sampler| sampler.decide(log_level, message))
+        {
+            Decision::Keep => None,
+            Decision::Sampled(rate) => Some(rate),
+            Decision::Drop => return,
         };
 
-        let log_event = LogEvent {
-            context: &self.context,
-            level: log_level.as_str(),
-            timestamp: FormattedTimestamp::new(self.timestamp_format),
-            timestamp_key: self.timestamp_key,
-            data,
-            message: message.as_ref(),
+        if let Some(rate_limiter) = &inner.rate_limiter {
+            let (verdict, overdue) = rate_limiter.check(log_level, message, caller);
+            if let Some(summary) = overdue {
+                inner.emit_summary(&summary);
+            }
+            match verdict {
+                Verdict::Allow => {}
+                Verdict::Suppress => return,
+                Verdict::SuppressAndSchedule(key, due) => {
+                    self.schedule_summary(key, due);
+                    return;
+                }
+            }
+        }
+
+        let extras = Extras {
+            caller,
+            sample_rate,
+            backfill: false,
+            span,
+            error,
         };
+        inner.emit(log_level, message, extras, custom_data);
+    }
+
+    // The worker reports what was suppressed once the window is over
+    fn schedule_summary(&self, key: Key, due: Instant) {
+        // Weak so a pending summary doesn't keep the logger alive, `LoggerInner::drop` reports the rest
+        let logger = Arc::downgrade(&self.inner);
+        self.inner.writer.defer(
+            due,
+            Box::new(move |batch| {
+                let Some(inner) = logger.upgrade() else {
+                    return;
+                };
+                let Some(summary) = inner
+                    .rate_limiter
+                    .as_ref()
+                    .and_then(|rate_limiter| rate_limiter.take_summary(&key))
+                else {
+                    return;
+                };
+                let mut buf = Vec::new();
+                if inner
+                    .encode(
+                        &mut buf,
+                        inner.now(),
+                        summary.level,
+                        &summary.message(),
+                        Extras::summary(&summary),
+                        &summary,
+                    )
+                    .is_ok()
+                {
+                    buf.push(b'\n');
+                    // Already on the worker, so this is where the line's number is taken
+                    let sequence = inner
+                        .sequence
+                        .as_ref()
+                        .and_then(|sequencer| sequencer.locate(&buf));
+                    let _ = sequence::write_line(batch, &buf, sequence.as_ref());
+                }
+            }),
+        );
+    }
+}
+
+// The parts of an event besides its level, message and data
+#[derive(Clone, Copy)]
+pub(crate) struct Extras<'a> {
+    pub(crate) caller: Option<&'static Location<'static>>,
+    pub(crate) sample_rate: Option<f64>,
+    pub(crate) backfill: bool,
+    pub(crate) span: Option<&'a Span>,
+    pub(crate) error: Option<ErrorData<'a>>,
+}
+
+impl Extras<'_> {
+    fn summary(summary: &Summary) -> Self {
+        Extras {
+            caller: summary.location,
+            sample_rate: None,
+            backfill: false,
+            span: None,
+            error: None,
+        }
+    }
+}
 
-        // get a buffer from the pool instead of creating one each time
-        let mut buf = self
-            .buffer_pool
-            .pop()
-            .unwrap_or_else(|| Vec::with_capacity(self.buffer_pool_initial_capacity));
-        buf.clear(); // just in case
+impl LoggerInner {
+    fn should_flush(&self, log_level: LogLevel) -> bool {
+        self.flush_on_level
+            .is_some_and(|flush_level| log_level.severity() >= flush_level.severity())
+    }
 
-        let result = if self.pretty {
-            serde_json::to_writer_pretty(&mut buf, &log_event)
+    fn serialize_event<Data: Serialize>(
+        &self,
+        buf: &mut Vec<u8>,
+        log_event: &LogEvent<'_, Data>,
+    ) -> serde_json::Result<()> {
+        if self.pretty {
+            serde_json::to_writer_pretty(buf, log_event)
         } else {
-            serde_json::to_writer(&mut buf, &log_event)
-        };
+            serde_json::to_writer(buf, log_event)
+        }
+    }
+
+    fn emit_summary(&self, summary: &Summary) {
+        self.emit(
+            summary.level,
+            &summary.message(),
+            Extras::summary(summary),
+            summary,
+        );
+    }
+
+    fn now(&self) -> FormattedTimestamp<'_> {
+        FormattedTimestamp::new(&self.timestamp_format, self.timezone)
+    }
 
-        if let Err(e) = result {
+    // Serializes the event and hands it to the writer
+    fn emit<CustomData: Serialize>(
+        &self,
+        log_level: LogLevel,
+        message: &str,
+        extras: Extras<'_>,
+        custom_data: CustomData,
+    ) {
+        let mut buf = self.writer.buffer();
+        let timestamp = self.now();
+
+        if let Err(e) = self.encode(&mut buf, timestamp, log_level, message, extras, custom_data) {
             eprintln!("Error ocurred converting log event to bytes. Error: {e}");
-            // Extra check, re-clear the buffer before putting it back
-            buf.clear();
 
             // Return the buffer to the pool if we errored
-            let _ = self.buffer_pool.push(buf);
+            self.writer.discard(buf);
             return;
         };
 
         // newline between logs
         buf.push(b'\n');
 
-        if let Some(sender) = &self.sender {
-            let _ = sender.send(buf);
+        let repeat = self
+            .collapse_repeats
+            .and_then(|timeout| Repeat::new(std::ptr::from_ref(self).addr(), &buf, timeout));
+        let sequence = self
+            .sequence
+            .as_ref()
+            .and_then(|sequencer| sequencer.locate(&buf));
+        self.writer.write(buf, repeat, sequence);
+
+        if self.should_flush(log_level) {
+            if self.flush_on_level_blocking {
+                self.writer.flush();
+            } else {
+                self.writer.request_flush();
+            }
         }
     }
 
-    fn warn_every_n(pct_oversized: f64, total_messages_count: usize) -> usize {
-        // If 50% of our logs are oversized, give a warning every 1 in 200 logs
-        // small minimum of 1000 to keep noise down right from the beginning
-        match pct_oversized {
-            p if p > 50.0 && total_messages_count > 1000 => 200,
-            p if p > 30.0 && total_messages_count > 1000 => 500,
-            _ => 1000, // Default to 1 in 1000 logs
+    // Encodes an event below `min_level` into the fingers crossed buffer instead of writing it
+    fn hold_back<CustomData: Serialize>(
+        &self,
+        backfill: &Backfill,
+        log_level: LogLevel,
+        message: &str,
+        extras: Extras<'_>,
+        custom_data: CustomData,
+    ) {
+        let mut buf = self.writer.buffer();
+        if let Err(e) = self.encode(
+            &mut buf,
+            self.now(),
+            log_level,
+            message,
+            extras,
+            custom_data,
+        ) {
+            eprintln!("Error ocurred converting log event to bytes. Error: {e}");
+            self.writer.discard(buf);
+            return;
         }
-    }
+        buf.push(b'\n');
 
-    pub(crate) fn handle_messages(
-        worker: Receiver<Vec<u8>>,
-        buffer_pool: Arc<ArrayQueue<Vec<u8>>>,
-        buffer_pool_max_capacity: usize,
-        buffer_pool_initial_capacity: usize,
-        flush_at_bytes: usize,
-        flush_at_messages: usize,
-        flush_interval: Duration,
-    ) -> std::thread::JoinHandle<()> {
-        // Spawn a dedicated thread for logs
-        std::thread::spawn(move || {
-            let mut batch = Vec::<u8>::with_capacity(flush_at_bytes);
-            let mut batch_message_count: usize = 0;
-            let mut oversized_messages_count: usize = 0;
-            let mut oversized_messages_window = Instant::now();
-            let mut total_messages_count: usize = 0;
-
-            loop {
-                match worker.recv_timeout(flush_interval) {
-                    Ok(mut log_buffer) => {
-                        batch.extend_from_slice(&log_buffer);
-                        batch_message_count += 1; // this resets per batch
-
-                        // Reset the window if its expired
-                        if oversized_messages_window.elapsed() > OVERSIZED_LOG_RESET_WINDOW {
-                            total_messages_count = 0;
-                            oversized_messages_count = 0;
-                            oversized_messages_window = Instant::now();
-                        }
-
-                        // this is global so that we can give a % of oversized logs
-                        total_messages_count += 1;
-
-                        // Check if the log that just came in made the vec grow
-                        // past a certain size and:
-                        // 1. Log a warning
-                        // 2. trim it down if it did.
-
-                        if log_buffer.capacity() > buffer_pool_max_capacity {
-                            let log_was_oversized = log_buffer.len() > buffer_pool_max_capacity;
-
-                            if log_was_oversized {
-                                oversized_messages_count += 1;
-
-                                // Check how many in the last N hours
-                                let percentage_of_oversized = oversized_messages_count as f64
-                                    / total_messages_count as f64
-                                    * 100.0;
-
-                                // Log a warning on first ocurrance or every N (set above)
-                                if oversized_messages_count == 1
-                                    || oversized_messages_count.is_multiple_of(Self::warn_every_n(
-                                        percentage_of_oversized,
-                                        total_messages_count,
-                                    ))
-                                {
-                                    // If its an oversized string, we obviously don't want to log the whole thing
-                                    let preview_len =
-                                        log_buffer.len().min(OVERSIZED_LOG_PREVIEW_LENGTH);
-                                    let oversized_log_preview = // get the actual text
-                                    String::from_utf8_lossy(&log_buffer[..preview_len]);
-
-                                    let truncation_note =
-                                        if log_buffer.len() > OVERSIZED_LOG_PREVIEW_LENGTH {
-                                            format!("... ({} bytes total)", log_buffer.len())
-                                        } else {
-                                            String::new()
-                                        };
-                                    eprintln!(
-                                        "SJL_WARN: You have logs that are greater than your buffer_pool_max_capacity ({} bytes). \
-                                    This log was {} bytes. Right now {percentage_of_oversized:.2}% of total logs are oversized. \
-                                    Consider increasing the buffer_pool_initial_capacity value if you see this log a lot. \
-                                    Log that triggered this: {oversized_log_preview}{truncation_note}",
-                                        buffer_pool_max_capacity,
-                                        log_buffer.len()
-                                    )
-                                }
-                            }
-                        }
-
-                        // Clear the buffer
-                        log_buffer.clear();
-                        // This has to come after clear() because shrink_to docs:
-                        // `The capacity will remain at least as large as both the length and the supplied value`
-                        // So if we shrink first with items still in it, it'll still be the size of the items inside
-                        // even though the capacity provided is smaller: max(len(), MAX_BUFFER_POOL_VECTOR_SIZE)
-                        // We could also drop the buffer here when it happens, the buffer pool size would shrink
-                        // by 1 and the we'd just get new Vec<u8>'s when/if we run out in the producer
-                        log_buffer.shrink_to(buffer_pool_initial_capacity);
-                        // and return it to the pool
-                        let _ = buffer_pool.push(log_buffer);
-
-                        // Happy path, flush logs
-                        if batch_message_count >= flush_at_messages || batch.len() >= flush_at_bytes
-                        {
-                            Logger::flush(&mut batch);
-                            batch_message_count = 0;
-                        }
-                    }
-                    // Flush regardless of what happened, we might be shutting down
-                    Err(RecvTimeoutError::Disconnected) => {
-                        Logger::flush(&mut batch);
-                        break;
-                    }
-                    Err(RecvTimeoutError::Timeout) => {
-                        Logger::flush(&mut batch);
-                        batch_message_count = 0;
-                        // Don't break to keep the loop going
-                    }
-                }
-            }
-        })
+        if let Some(evicted) = backfill.push(buf) {
+            self.writer.discard(evicted);
+        }
     }
 
-    fn flush(batch: &mut Vec<u8>) {
-        if batch.is_empty() {
-            return;
-        }
+    // Serializes one event into `buf`, keeping it under `max_line_bytes` when that's set
+    fn encode<CustomData: Serialize>(
+        &self,
+        buf: &mut Vec<u8>,
+        timestamp: FormattedTimestamp<'_>,
+        log_level: LogLevel,
+        message: &str,
+        extras: Extras<'_>,
+        custom_data: CustomData,
+    ) -> serde_json::Result<()> {
+        let truncated = Cell::new(self.context_truncated);
 
-        // todo make this configurable
-        let mut out = std::io::stderr().lock();
-        let _ = out.write_all(batch);
-        let _ = out.flush();
+        let message = self.sanitizer.as_ref().map_or_else(
+            || Cow::Borrowed(message),
+            |sanitizer| sanitizer.sanitize_str(message, &truncated),
+        );
 
-        batch.clear();
-    }
-}
+        let caller = extras.caller.filter(|_| self.caller_location);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // Don't serialize the empty data: () in the log event to null, just skip it
+        let data = if size_of::<CustomData>() == 0 {
+            None
+        } else {
+            Some(EventData::Value(Sanitized::new(
+                &custom_data,
+                self.sanitizer.as_ref(),
+                &truncated,
+            )))
+        };
 
-    #[test]
-    fn test_warn_every_n_defaults_to_100_when_under_min_count() {
-        // even if almost everything is oversized, if we havent hit am inimum don't log anything
-        assert_eq!(Logger::warn_every_n(99.9999, 500), 1000);
-        assert_eq!(Logger::warn_every_n(99.9999, 1000), 1000)
-    }
+        // Only look up the current thread if we need its name
+        let thread = self
+            .thread_name_key
+            .as_ref()
+            .map(|key| (key, std::thread::current()));
 
-    #[test]
-    fn test_warn_every_n_returns_200_when_majority_oversized() {
-        assert_eq!(Logger::warn_every_n(50.1, 1001), 200);
-        assert_eq!(Logger::warn_every_n(75.0, 10_000_000), 200);
-        assert_eq!(Logger::warn_every_n(100.0, 1001), 200);
-    }
+        let mut log_event = LogEvent {
+            // A placeholder, the number is filled in as the line is written so gaps mean lost lines.
+            // Held back events are written out of order, if at all, so they don't get one
+            sequence: self
+                .sequence
+                .as_ref()
+                .filter(|_| !extras.backfill)
+                .map(|sequencer| (sequencer.key.as_ref(), 0)),
+            thread_name: thread
+                .as_ref()
+                .map(|(key, thread)| (key.as_ref(), thread.name())),
+            caller,
+            sample_rate: extras.sample_rate,
+            backfill: extras.backfill,
+            span: extras.span,
+            error: extras.error.as_ref().map(|error| {
+                EventData::Value(Sanitized::new(error, self.sanitizer.as_ref(), &truncated))
+            }),
+            error_key: &self.error_key,
+            thread_id: self
+                .thread_id_key
+                .as_deref()
+                .map(|key| (key, metadata::current_thread_id())),
+            context: &self.context,
+            level: log_level.as_str(),
+            timestamp,
+            timestamp_key: &self.timestamp_key,
+            data: data.as_ref(),
+            message: message.as_ref(),
+            truncated: &truncated,
+        };
 
-    #[test]
-    fn test_warn_every_n_returns_500_when_sorta_oversized() {
-        assert_eq!(Logger::warn_every_n(32.0, 1001), 500);
-        assert_eq!(Logger::warn_every_n(42.0, 10_000_000), 500);
+        self.serialize_event(buf, &log_event)?;
+
+        // Too big even after the other limits. Drop `data` first, then the error, then cut the message
+        let overflow = |buf: &Vec<u8>| {
+            self.max_line_bytes
+                .map_or(0, |max| buf.len().saturating_sub(max))
+        };
+        let data_marker;
+        if overflow(buf) > 0 && log_event.data.is_some() {
+            data_marker = EventData::Truncated(Marker::size_of(&custom_data));
+            log_event.data = Some(&data_marker);
+            truncated.set(true);
+            buf.clear();
+            self.serialize_event(buf, &log_event)?;
+        }
+        if overflow(buf) > 0
+            && let Some(error) = &extras.error
+        {
+            log_event.error = Some(EventData::Truncated(Marker::size_of(error)));
+            truncated.set(true);
+            buf.clear();
+            self.serialize_event(buf, &log_event)?;
+        }
+        let marker_len = Marker::Bytes(log_event.message.len()).to_string().len();
+        // Room for the `truncated` key too if this is what adds it
+        let flag_len = || {
+            if truncated.get() {
+                0
+            } else {
+                TRUNCATED_FLAG_LEN
+            }
+        };
+        // Even an empty message wouldn't fit next to the context and everything else,
+        // so the line is cut down to what's needed to know an event was there
+        let no_context = Map::new();
+        if overflow(buf) > 0 && overflow(buf) + marker_len + flag_len() > log_event.message.len() {
+            log_event.strip(&no_context);
+            truncated.set(true);
+            buf.clear();
+            self.serialize_event(buf, &log_event)?;
+        }
+        let short_message;
+        if overflow(buf) > 0 {
+            let keep = log_event
+                .message
+                .len()
+                .saturating_sub(overflow(buf) + marker_len + flag_len());
+            if let Some(shortened) = sanitize::truncate_str(log_event.message, keep) {
+                short_message = shortened;
+                log_event.message = &short_message;
+                truncated.set(true);
+                buf.clear();
+                self.serialize_event(buf, &log_event)?;
+            }
+        }
+        Ok(())
     }
+}
 
-    #[test]
-    fn test_warn_every_n_handles_nan_default() {
-        assert_eq!(Logger::warn_every_n(f64::NAN, 1001), 1000);
+impl Drop for LoggerInner {
+    // Whatever is still suppressed is reported before the writer shuts down
+    fn drop(&mut self) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            for summary in rate_limiter.take_all_summaries() {
+                self.emit_summary(&summary);
+            }
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::atomic::Ordering, time::Duration};
 
     #[test]
     fn test_should_log_min_level_debug() {
@@ -344,4 +671,384 @@ mod tests {
         let logger = LoggerOptions::default().min_level(LogLevel::Error).init();
         assert_eq!(logger.should_log(LogLevel::Error), true);
     }
+
+    #[test]
+    fn test_sequence_only_counts_emitted_events() {
+        let logger = LoggerOptions::default()
+            .min_level(LogLevel::Info)
+            .sequence(true)
+            .init();
+
+        logger.info("one", ());
+        logger.debug("filtered", ());
+        logger.warn("two", ());
+        logger.flush();
+
+        let sequencer = logger.inner.sequence.as_ref().unwrap();
+        assert_eq!(sequencer.next.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_fingers_crossed_holds_back_events_below_min_level() {
+        let (options, captured) = LoggerOptions::default()
+            .min_level(LogLevel::Info)
+            .fingers_crossed(2, LogLevel::Error)
+            .sequence(true)
+            .captured();
+        let logger = options.init();
+        let backfill = logger.inner.backfill.as_ref().unwrap();
+
+        logger.debug("one", ());
+        logger.debug("two", ());
+        logger.debug("three", ());
+        logger.info("written", ());
+
+        let held: Vec<Value> = backfill
+            .take()
+            .iter()
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(held.len(), 2);
+        assert_eq!(held[0]["message"], "two");
+        assert_eq!(held[1]["message"], "three");
+        assert_eq!(held[1]["backfill"], true);
+        // Only the Info event got a number
+        assert!(held[1].get("seq").is_none());
+        logger.flush();
+        let sequencer = logger.inner.sequence.as_ref().unwrap();
+        assert_eq!(sequencer.next.load(Ordering::Relaxed), 1);
+
+        // The trigger writes out what was held before its own line
+        logger.debug("four", ());
+        logger.debug("five", ());
+        logger.error("boom", ());
+        logger.flush();
+        assert!(backfill.take().is_empty());
+
+        let written: Vec<(String, bool)> = captured
+            .lines()
+            .iter()
+            .map(|line| {
+                let backfill = line
+                    .get("backfill")
+                    .is_some_and(|backfill| backfill == true);
+                (line["message"].as_str().unwrap().to_string(), backfill)
+            })
+            .collect();
+        assert_eq!(
+            written,
+            [
+                ("written".to_string(), false),
+                ("four".to_string(), true),
+                ("five".to_string(), true),
+                ("boom".to_string(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_should_flush_is_off_by_default() {
+        let logger = LoggerOptions::default().init();
+
+        assert!(!logger.inner.should_flush(LogLevel::Error));
+    }
+
+    #[test]
+    fn test_should_flush_at_or_above_level() {
+        let logger = LoggerOptions::default()
+            .flush_on_level(LogLevel::Warn)
+            .init();
+
+        assert!(!logger.inner.should_flush(LogLevel::Debug));
+        assert!(!logger.inner.should_flush(LogLevel::Info));
+        assert!(logger.inner.should_flush(LogLevel::Warn));
+        assert!(logger.inner.should_flush(LogLevel::Error));
+    }
+
+    #[test]
+    fn test_blocking_flush_on_level() {
+        let (options, captured) = LoggerOptions::default()
+            .flush_interval(Duration::from_hours(1))
+            .flush_on_level(LogLevel::Error)
+            .flush_on_level_blocking(true)
+            .captured();
+        let logger = options.init();
+
+        logger.info("batched", ());
+        assert!(captured.lines().is_empty());
+
+        logger.error("written before this returns", ());
+        let lines = captured.lines();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["message"], "written before this returns");
+    }
+
+    #[cfg(feature = "scrub")]
+    #[test]
+    fn test_scrubs_message_and_data() {
+        let logger = LoggerOptions::default()
+            .scrub(crate::Scrubber::email())
+            .scrub(crate::Scrubber::ipv4())
+            .init();
+
+        logger.info("signup from saul@goodman.law", ());
+        logger.info(
+            "request",
+            serde_json::json!({"ip": "10.0.0.1", "path": "/health"}),
+        );
+        // `IpAddr` goes through `collect_str`
+        logger.info("peer", "10.0.0.2".parse::<std::net::IpAddr>().unwrap());
+        logger.info("nothing to scrub", serde_json::json!({"path": "/health"}));
+
+        assert_eq!(logger.scrubbed_count(), 3);
+    }
+
+    #[cfg(feature = "scrub")]
+    #[test]
+    fn test_scrubs_errors() {
+        let (options, captured) = LoggerOptions::default()
+            .scrub(crate::Scrubber::email())
+            .synchronous(true)
+            .captured();
+        let logger = options.init();
+
+        let e = std::io::Error::other("no account for saul@goodman.law");
+        logger.error_with("login failed", &e, ());
+
+        let lines = captured.lines();
+        assert_eq!(lines[0]["error"]["message"], "no account for [REDACTED]");
+        assert_eq!(logger.scrubbed_count(), 1);
+    }
+
+    fn encode_line<CustomData: Serialize>(
+        logger: &Logger,
+        message: &str,
+        custom_data: CustomData,
+    ) -> (usize, Value) {
+        let mut buf = Vec::new();
+        let extras = Extras {
+            caller: Some(Location::caller()),
+            sample_rate: None,
+            backfill: false,
+            span: logger.span.as_deref(),
+            error: None,
+        };
+        logger
+            .inner
+            .encode(
+                &mut buf,
+                logger.inner.now(),
+                LogLevel::Info,
+                message,
+                extras,
+                custom_data,
+            )
+            .unwrap();
+        (buf.len(), serde_json::from_slice(&buf).unwrap())
+    }
+
+    #[test]
+    fn test_span_ids_are_added_to_events() {
+        let logger = LoggerOptions::default()
+            .context("trace_id", "from context")
+            .init();
+        let checkout = logger.span("checkout");
+        let payment = checkout.span("payment");
+
+        let (_, outside) = encode_line(&logger, "outside", ());
+        let (_, root) = encode_line(&checkout, "root", ());
+        let (_, child) = encode_line(&payment, "child", ());
+
+        assert_eq!(outside["trace_id"], "from context");
+        assert!(outside.get("span_id").is_none());
+
+        assert_eq!(root["trace_id"].as_str().unwrap().len(), 32);
+        assert_eq!(root["span_id"].as_str().unwrap().len(), 16);
+        assert!(root.get("parent_span_id").is_none());
+
+        assert_eq!(child["trace_id"], root["trace_id"]);
+        assert_eq!(child["parent_span_id"], root["span_id"]);
+        assert_ne!(child["span_id"], root["span_id"]);
+
+        // A separate span of the plain logger starts its own trace
+        let (_, other) = encode_line(&logger.span("other"), "other", ());
+        assert_ne!(other["trace_id"], root["trace_id"]);
+    }
+
+    #[test]
+    fn test_trace_context_is_added_to_events() {
+        let logger = LoggerOptions::default().init();
+        assert!(logger.trace_context().is_none());
+
+        let trace_context =
+            TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").unwrap();
+        let request = logger.with_trace_context(&trace_context);
+        let (_, line) = encode_line(&request, "handling", ());
+
+        assert_eq!(line["trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(line["parent_span_id"], "00f067aa0ba902b7");
+        assert_ne!(line["span_id"], "00f067aa0ba902b7");
+        assert_eq!(line["trace_flags"], "00");
+
+        // Calls made while handling the request are children of this hop, not the caller
+        let outgoing = request.trace_context().unwrap();
+        assert_eq!(
+            outgoing.traceparent(),
+            format!(
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-{}-00",
+                line["span_id"].as_str().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_continued_trace_is_written_out() {
+        let (options, captured) = LoggerOptions::default().synchronous(true).captured();
+        let logger = options.init();
+        let incoming =
+            TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+                .unwrap()
+                .with_tracestate("congo=t61rcWkgMzE");
+
+        let request = logger.with_trace_context(&incoming);
+        request.info("handling", ());
+
+        let lines = captured.lines();
+        let line = &lines[0];
+        assert_eq!(line["trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(line["parent_span_id"], "00f067aa0ba902b7");
+        assert_eq!(line["trace_flags"], "01");
+        let span_id = line["span_id"].as_str().unwrap();
+        assert_eq!(span_id.len(), 16);
+        assert_ne!(span_id, "00f067aa0ba902b7");
+
+        let outgoing = request.trace_context().unwrap();
+        assert_eq!(
+            outgoing.traceparent(),
+            format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{span_id}-01")
+        );
+        assert_eq!(outgoing.tracestate(), Some("congo=t61rcWkgMzE"));
+    }
+
+    #[test]
+    fn test_max_line_bytes_drops_data_first() {
+        let logger = LoggerOptions::default().max_line_bytes(200).init();
+
+        let (len, line) = encode_line(&logger, "upload", "x".repeat(1_000));
+
+        assert!(len <= 200);
+        assert_eq!(line["message"], "upload");
+        // 1000 x's and the two quotes
+        assert_eq!(line["data"], "…(truncated 1002 bytes)");
+        assert_eq!(line["truncated"], true);
+    }
+
+    #[test]
+    fn test_max_line_bytes_cuts_message_when_data_is_not_enough() {
+        let logger = LoggerOptions::default().max_line_bytes(200).init();
+
+        let (len, line) = encode_line(&logger, &"m".repeat(1_000), ());
+
+        assert!(len <= 200);
+        assert!(line["message"].as_str().unwrap().ends_with("bytes)"));
+        assert_eq!(line["truncated"], true);
+    }
+
+    #[test]
+    fn test_max_line_bytes_drops_the_error_before_the_message() {
+        let logger = LoggerOptions::default().max_line_bytes(200).init();
+        let e = std::io::Error::other("e".repeat(1_000));
+        let mut buf = Vec::new();
+        let extras = Extras {
+            caller: Some(Location::caller()),
+            sample_rate: None,
+            backfill: false,
+            span: None,
+            error: Some(ErrorData::new(&e)),
+        };
+        logger
+            .inner
+            .encode(
+                &mut buf,
+                logger.inner.now(),
+                LogLevel::Error,
+                "upload failed",
+                extras,
+                (),
+            )
+            .unwrap();
+        let line: Value = serde_json::from_slice(&buf).unwrap();
+
+        assert!(buf.len() <= 200);
+        assert_eq!(line["message"], "upload failed");
+        assert!(line["error"].as_str().unwrap().starts_with("…(truncated"));
+        assert_eq!(line["truncated"], true);
+    }
+
+    #[test]
+    fn test_string_limits_apply_to_the_error() {
+        let (options, captured) = LoggerOptions::default()
+            .max_string_len(10)
+            .synchronous(true)
+            .captured();
+        let logger = options.init();
+
+        let e = std::io::Error::other("e".repeat(100));
+        logger.error_with("failed", &e, ());
+
+        let line = &captured.lines()[0];
+        assert!(
+            line["error"]["message"]
+                .as_str()
+                .unwrap()
+                .ends_with("…(truncated 90 bytes)")
+        );
+        assert_eq!(line["truncated"], true);
+    }
+
+    #[test]
+    fn test_max_line_bytes_falls_back_when_context_is_too_big() {
+        let logger = LoggerOptions::default()
+            .context("build", "b".repeat(500))
+            .sequence(true)
+            .caller_location(true)
+            .max_line_bytes(200)
+            .init();
+
+        let (len, line) = encode_line(&logger, "deployed", "x".repeat(1_000));
+
+        assert!(len <= 200);
+        assert_eq!(line["message"], "deployed");
+        assert_eq!(line["seq"], 0);
+        assert_eq!(line["truncated"], true);
+        assert!(line.get("build").is_none());
+        assert!(line.get("caller").is_none());
+        assert!(line.get("data").is_none());
+    }
+
+    #[test]
+    fn test_small_lines_are_not_marked() {
+        let logger = LoggerOptions::default()
+            .max_line_bytes(1_000)
+            .max_string_len(100)
+            .init();
+
+        let (_, line) = encode_line(&logger, "ok", serde_json::json!({"a": "b"}));
+
+        assert_eq!(line["data"], serde_json::json!({"a": "b"}));
+        assert!(line.get("truncated").is_none());
+    }
+
+    #[test]
+    fn test_truncated_context_marks_every_line() {
+        let logger = LoggerOptions::default()
+            .context("build", "y".repeat(50))
+            .max_string_len(10)
+            .init();
+
+        let (_, line) = encode_line(&logger, "ok", ());
+
+        assert_eq!(line["build"], "yyyyyyyyyy…(truncated 40 bytes)");
+        assert_eq!(line["truncated"], true);
+    }
 }
@@ -1,10 +1,32 @@
-use std::{sync::Arc, time::Duration};
+use std::{borrow::Cow, cell::Cell, sync::Arc, time::Duration};
 
-use crossbeam_queue::ArrayQueue;
 use serde::Serialize;
 use serde_json::{Map, Value, map::Entry};
 
-use crate::{Logger, log_level::LogLevel};
+use crate::{
+    Logger,
+    backfill::{BACKFILL_KEY, Backfill},
+    collapse::{FIRST_TIMESTAMP_KEY, LAST_TIMESTAMP_KEY, REPEAT_COUNT_KEY},
+    error_data::DEFAULT_ERROR_KEY,
+    ids,
+    log_event::{CALLER_KEY, TRUNCATED_KEY},
+    log_level::LogLevel,
+    logger::LoggerInner,
+    metadata::{
+        self, DEFAULT_HOSTNAME_KEY, DEFAULT_PID_KEY, DEFAULT_SEQUENCE_KEY, DEFAULT_THREAD_ID_KEY,
+        DEFAULT_THREAD_NAME_KEY, LOGGER_INSTANCE_ID_KEY,
+    },
+    rate_limit::{RateLimitKey, RateLimiter},
+    sampling::{SAMPLE_RATE_KEY, SampleRule, Sampler},
+    sanitize::{Limits, RedactionStyle, Sanitized, Sanitizer},
+    sequence::Sequencer,
+    span::SPAN_KEYS,
+    timestamp::{TimestampFormat, Timezone},
+    writer::{Sink, Writer},
+};
+
+#[cfg(feature = "scrub")]
+use crate::Scrubber;
 
 const DEFAULT_FLUSH_AT_BYTES: usize = 64 * 2048;
 const DEFAULT_FLUSH_AT_MESSAGES: usize = 100;
@@ -15,11 +37,18 @@ const DEFAULT_BUFFER_POOL_MAX_CAPACITY: usize = 20 * DEFAULT_BUFFER_POOL_INITIAL
 const RESERVED_FIELD_NAMES: &[&str; 3] = &["level", "message", "data"];
 
 #[must_use = "LoggerOptions does nothing until you call `.init()`"]
+// Each bool is its own independent option, not a state machine
+#[allow(clippy::struct_excessive_bools)]
 pub struct LoggerOptions {
     // Batching
     pub(crate) flush_at_bytes: usize,
     pub(crate) flush_at_messages: usize,
     pub(crate) flush_interval: Duration,
+    pub(crate) flush_on_level: Option<LogLevel>,
+    pub(crate) flush_on_level_blocking: bool,
+    pub(crate) synchronous: bool,
+    pub(crate) writer: Option<Writer>,
+    pub(crate) sink: Sink,
 
     // Buffer pool
     pub(crate) buffer_pool_size: usize,
@@ -29,9 +58,50 @@ pub struct LoggerOptions {
     // Behavior
     pub(crate) context: Map<String, Value>,
     pub(crate) min_level: LogLevel,
-    pub(crate) timestamp_format: Option<&'static str>,
-    pub(crate) timestamp_key: &'static str, // TODO allow overriding
+    pub(crate) timestamp_format: Option<Cow<'static, str>>,
+    pub(crate) timestamp_key: Cow<'static, str>,
+    pub(crate) error_key: Cow<'static, str>,
+    pub(crate) timezone: Timezone,
     pub(crate) pretty: bool,
+
+    // Process metadata
+    pub(crate) pid: bool,
+    pub(crate) pid_key: Cow<'static, str>,
+    pub(crate) hostname: bool,
+    pub(crate) hostname_key: Cow<'static, str>,
+    pub(crate) thread_name: bool,
+    pub(crate) thread_name_key: Cow<'static, str>,
+    pub(crate) thread_id: bool,
+    pub(crate) thread_id_key: Cow<'static, str>,
+    pub(crate) sequence: bool,
+    pub(crate) sequence_key: Cow<'static, str>,
+    pub(crate) caller_location: bool,
+
+    // Redaction
+    pub(crate) redact_keys: Vec<String>,
+    pub(crate) redaction_style: RedactionStyle,
+    #[cfg(feature = "scrub")]
+    pub(crate) scrubbers: Vec<Scrubber>,
+
+    // Size limits
+    pub(crate) max_line_bytes: Option<usize>,
+    pub(crate) limits: Limits,
+
+    // Sampling, indexed by `LogLevel::severity()`
+    pub(crate) sample_rules: [Option<SampleRule>; 4],
+
+    // Rate limiting
+    pub(crate) rate_limit: Option<(u32, Duration)>,
+    pub(crate) rate_limit_key: RateLimitKey,
+
+    // How long repeats of the same event are held before their summary is written
+    pub(crate) collapse_repeats: Option<Duration>,
+
+    // Fingers crossed buffering, how many events below `min_level` to keep and what writes them out
+    pub(crate) fingers_crossed: Option<(usize, LogLevel)>,
+
+    // Level of the enter/exit events logged by spans, `None` to not log them
+    pub(crate) span_events: Option<LogLevel>,
 }
 
 impl Default for LoggerOptions {
@@ -42,9 +112,39 @@ impl Default for LoggerOptions {
             flush_at_messages: DEFAULT_FLUSH_AT_MESSAGES,
             min_level: LogLevel::Debug,
             flush_interval: DEFAULT_FLUSH_INTERVAL,
+            flush_on_level: None,
+            flush_on_level_blocking: false,
+            synchronous: false,
+            writer: None,
+            sink: Sink::Stderr,
             timestamp_format: None,
-            timestamp_key: "timestamp",
+            timestamp_key: Cow::Borrowed("timestamp"),
+            error_key: Cow::Borrowed(DEFAULT_ERROR_KEY),
+            timezone: Timezone::Utc,
             pretty: false,
+            pid: false,
+            pid_key: Cow::Borrowed(DEFAULT_PID_KEY),
+            hostname: false,
+            hostname_key: Cow::Borrowed(DEFAULT_HOSTNAME_KEY),
+            thread_name: false,
+            thread_name_key: Cow::Borrowed(DEFAULT_THREAD_NAME_KEY),
+            thread_id: false,
+            thread_id_key: Cow::Borrowed(DEFAULT_THREAD_ID_KEY),
+            sequence: false,
+            sequence_key: Cow::Borrowed(DEFAULT_SEQUENCE_KEY),
+            caller_location: false,
+            redact_keys: Vec::new(),
+            redaction_style: RedactionStyle::Mask,
+            #[cfg(feature = "scrub")]
+            scrubbers: Vec::new(),
+            max_line_bytes: None,
+            limits: Limits::default(),
+            sample_rules: [None; 4],
+            rate_limit: None,
+            rate_limit_key: RateLimitKey::Message,
+            collapse_repeats: None,
+            fingers_crossed: None,
+            span_events: None,
             buffer_pool_size: DEFAULT_BUFFER_POOL_SIZE,
             buffer_pool_initial_capacity: DEFAULT_BUFFER_POOL_INITIAL_CAPACITY,
             buffer_pool_max_capacity: DEFAULT_BUFFER_POOL_MAX_CAPACITY,
@@ -84,8 +184,7 @@ impl LoggerOptions {
             // If we failed to parse, return an error
             Err(serialize_error) => {
                 eprintln!(
-                    "Error serializing context value for key '{}'. It will not be included. \nError: {}",
-                    key, serialize_error
+                    "Error serializing context value for key '{key}'. It will not be included. \nError: {serialize_error}"
                 );
             }
         };
@@ -178,6 +277,45 @@ impl LoggerOptions {
         self
     }
 
+    /// Flush the batch as soon as a log at or above this level comes in instead of waiting
+    /// for the other thresholds, so the last error before a crash isn't stuck in memory.
+    /// Default is off
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn flush_on_level(mut self, level: LogLevel) -> Self {
+        self.flush_on_level = Some(level);
+        self
+    }
+
+    /// When used with `flush_on_level`, `.error()` etc. won't return until the log has been written.
+    /// This blocks the calling thread on the write, so only use it for levels that are rare.
+    /// Default `false`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn flush_on_level_blocking(mut self, blocking: bool) -> Self {
+        self.flush_on_level_blocking = blocking;
+        self
+    }
+
+    /// Write each log from the calling thread as soon as it's logged instead of batching them
+    /// on a background thread. Meant for short-lived CLIs and tests: nothing is lost if the process
+    /// exits through `std::process::exit`, but every log pays for a write to stderr.
+    /// The batching options are ignored in this mode. Default `false`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn synchronous(mut self, synchronous: bool) -> Self {
+        self.synchronous = synchronous;
+        self
+    }
+
+    /// Send logs through an existing writer instead of spawning a new thread for this logger.
+    /// Get one from another logger with `Logger::writer()`. Loggers sharing a writer can have
+    /// different context, levels, etc. but share one background thread, one buffer pool and
+    /// one ordered output stream. The batching, buffer pool and `synchronous` options are
+    /// ignored since those belong to the writer.
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn writer(mut self, writer: &Writer) -> Self {
+        self.writer = Some(writer.clone());
+        self
+    }
+
     /// Minimum log level to use. Anything below will not be logged.
     /// From left to right: Debug, Info, Warn, Error. Default is Debug.
     /// If you set the `min_level` to Warn, then Debug and Info WILL NOT show in your logs.
@@ -187,14 +325,116 @@ impl LoggerOptions {
         self
     }
 
+    /// Keeps each `level` event with probability `rate`, e.g. `.sample_rate(LogLevel::Info, 0.1)` for 10%.
+    /// Kept events get a `"sample_rate": 0.1` field so backends can re-weight counts.
+    /// Decided before anything is serialized, so dropped events are almost free.
+    /// Replaces any `.sample_every_nth()` for the same level. `rate` must be in (0, 1]
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn sample_rate(self, level: LogLevel, rate: f64) -> Self {
+        if rate > 0.0 && rate <= 1.0 {
+            self.sample(level, SampleRule::Rate(rate))
+        } else {
+            eprintln!("Provided 'sample_rate' {rate} is invalid, {level:?} logs won't be sampled");
+            self
+        }
+    }
+
+    /// Keeps the first of every `n` `level` events with the same message, e.g. every 50th.
+    /// Kept events get `"sample_rate": 1/n`. Replaces any `.sample_rate()` for the same level
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn sample_every_nth(self, level: LogLevel, n: u64) -> Self {
+        if n == 0 {
+            eprintln!("Provided 'sample_every_nth' is invalid, {level:?} logs won't be sampled");
+            self
+        } else {
+            self.sample(level, SampleRule::EveryNth(n))
+        }
+    }
+
+    fn sample(mut self, level: LogLevel, rule: SampleRule) -> Self {
+        if let Some(slot) = self.sample_rules.get_mut(level.severity()) {
+            *slot = Some(rule);
+        }
+        self
+    }
+
+    /// Allows at most `max_events` events with the same message per `per`, e.g. `.rate_limit(10, Duration::from_secs(1))`.
+    /// Bursts of up to `max_events` go through, after that tokens refill evenly over `per`.
+    ///
+    /// Once a key's window ends the worker logs `"suppressed 9876 similar events for key X"`
+    /// at the same level with `{"key": "X", "suppressed": 9876}` as `data`.
+    /// In synchronous mode that happens on the next event for the key instead.
+    ///
+    /// Up to 10,000 keys are tracked. Past that, events for new keys aren't limited until
+    /// an old key has been quiet long enough to be dropped
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn rate_limit(mut self, max_events: u32, per: Duration) -> Self {
+        if max_events == 0 || per.is_zero() {
+            eprintln!("Provided 'rate_limit' is invalid, logs won't be rate limited");
+        } else {
+            self.rate_limit = Some((max_events, per));
+        }
+        self
+    }
+
+    /// Group events for `.rate_limit()` by message or by the line that logged them. Default `RateLimitKey::Message`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn rate_limit_key(mut self, rate_limit_key: RateLimitKey) -> Self {
+        self.rate_limit_key = rate_limit_key;
+        self
+    }
+
+    /// Keeps the last `capacity` events below `min_level` in memory instead of dropping them.
+    /// They're thrown away as newer ones come in, unless an event at or above `trigger` is logged:
+    /// then the buffered ones are written out first, oldest first, each with `"backfill": true`.
+    ///
+    /// For example `.min_level(LogLevel::Info).fingers_crossed(100, LogLevel::Error)` writes
+    /// the last 100 Debug events before every Error. Buffered events are still serialized,
+    /// so they cost about as much as written ones
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn fingers_crossed(mut self, capacity: usize, trigger: LogLevel) -> Self {
+        if capacity == 0 {
+            eprintln!("Provided 'fingers_crossed' capacity is invalid, nothing will be buffered");
+        } else {
+            self.fingers_crossed = Some((capacity, trigger));
+        }
+        self
+    }
+
+    /// Log `"{name} entered"` when `Logger::span()` is called and `"{name} exited"` with `duration_ms`
+    /// once the span's logger is dropped, both at `level`. Off by default
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn span_events(mut self, level: LogLevel) -> Self {
+        self.span_events = Some(level);
+        self
+    }
+
+    /// Collapses runs of identical events (the same line apart from the timestamp) from this logger.
+    /// The first one is written as usual, the rest are held back and written as one event with
+    /// `repeat_count`, `first_timestamp` from the first event and `last_timestamp` once a different
+    /// event comes along or `timeout` passes.
+    ///
+    /// Collapsing happens on the background worker, so this does nothing in synchronous mode
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn collapse_repeats(mut self, timeout: Duration) -> Self {
+        if timeout.is_zero() {
+            eprintln!("Provided 'collapse_repeats' is invalid, repeats won't be collapsed");
+        } else {
+            self.collapse_repeats = Some(timeout);
+        }
+        self
+    }
+
     /// Set a custom timestamp format
     /// Use these guides as reference:
     /// <https://docs.rs/chrono/latest/chrono/#formatting-and-parsing> &
     /// <https://docs.rs/chrono/latest/chrono/format/strftime/index.html#specifiers>
     /// Default is RFC 3339 with millisecond precision (2024-01-15T14:30:00.123Z)
+    /// Accepts owned strings so it can come from a config file or env var.
+    /// The format is parsed once in `.init()`, which panics if it's invalid.
     #[must_use = "call `.init()` to create a Logger"]
-    pub fn timestamp_format(mut self, timestamp_format: &'static str) -> Self {
-        self.timestamp_format = Some(timestamp_format);
+    pub fn timestamp_format(mut self, timestamp_format: impl Into<Cow<'static, str>>) -> Self {
+        self.timestamp_format = Some(timestamp_format.into());
 
         self
     }
@@ -202,8 +442,27 @@ impl LoggerOptions {
     /// Remap the timestamp key from `timestamp` to something else like `time`
     /// Default is `timestamp`
     #[must_use = "call `.init()` to create a Logger"]
-    pub fn timestamp_key(mut self, timestamp_key: &'static str) -> Self {
-        self.timestamp_key = timestamp_key;
+    pub fn timestamp_key(mut self, timestamp_key: impl Into<Cow<'static, str>>) -> Self {
+        self.timestamp_key = timestamp_key.into();
+        self
+    }
+
+    /// Remap the key `error_with()` puts the error under from `error` to something else.
+    /// On events with an error, it replaces a context key with the same name.
+    /// Default is `error`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn error_key(mut self, error_key: impl Into<Cow<'static, str>>) -> Self {
+        self.error_key = error_key.into();
+        self
+    }
+
+    /// Time zone the timestamp is rendered in: UTC, the system's local time or a fixed offset.
+    /// When it isn't UTC, the default format ends with the offset (`2024-01-15T23:30:00.123+09:00`)
+    /// instead of `Z`. Custom formats are used as is, add `%:z` to them if you want the offset.
+    /// Default is UTC
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn timezone(mut self, timezone: Timezone) -> Self {
+        self.timezone = timezone;
         self
     }
 
@@ -214,6 +473,216 @@ impl LoggerOptions {
         self
     }
 
+    /// Adds the process id to every log line. It's looked up once in `.init()`.
+    /// Default `false`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn pid(mut self, pid: bool) -> Self {
+        self.pid = pid;
+        self
+    }
+
+    /// Rename the `pid` field. Default is `pid`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn pid_key(mut self, pid_key: impl Into<Cow<'static, str>>) -> Self {
+        self.pid_key = pid_key.into();
+        self
+    }
+
+    /// Adds the machine's hostname to every log line. It's looked up once in `.init()`.
+    /// Default `false`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn hostname(mut self, hostname: bool) -> Self {
+        self.hostname = hostname;
+        self
+    }
+
+    /// Rename the `hostname` field. Default is `hostname`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn hostname_key(mut self, hostname_key: impl Into<Cow<'static, str>>) -> Self {
+        self.hostname_key = hostname_key.into();
+        self
+    }
+
+    /// Adds the name of the thread that logged the event. Unnamed threads show up as `null`.
+    /// Default `false`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn thread_name(mut self, thread_name: bool) -> Self {
+        self.thread_name = thread_name;
+        self
+    }
+
+    /// Rename the thread name field. Default is `thread`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn thread_name_key(mut self, thread_name_key: impl Into<Cow<'static, str>>) -> Self {
+        self.thread_name_key = thread_name_key.into();
+        self
+    }
+
+    /// Adds a numeric id of the thread that logged the event.
+    /// Ids are unique per thread for the life of the process, but they're assigned by sjl
+    /// so they won't match the number in `std::thread::ThreadId`'s debug output.
+    /// Default `false`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn thread_id(mut self, thread_id: bool) -> Self {
+        self.thread_id = thread_id;
+        self
+    }
+
+    /// Rename the `thread_id` field. Default is `thread_id`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn thread_id_key(mut self, thread_id_key: impl Into<Cow<'static, str>>) -> Self {
+        self.thread_id_key = thread_id_key.into();
+        self
+    }
+
+    /// Adds a `seq` number to every log line that counts up from 0 for each logger,
+    /// along with a random `logger_instance_id` in the context.
+    /// Downstream you can group by `logger_instance_id` and look for gaps or out of order `seq`
+    /// values to find lines that were dropped or reordered between here and your log storage.
+    /// Default `false`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn sequence(mut self, sequence: bool) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    /// Rename the `seq` field. Default is `seq`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn sequence_key(mut self, sequence_key: impl Into<Cow<'static, str>>) -> Self {
+        self.sequence_key = sequence_key.into();
+        self
+    }
+
+    /// Adds the file and line that called `.info()`, `.warn()`, etc. as `"caller":{"file":"src/main.rs","line":12}`.
+    /// Uses `#[track_caller]` so no macros are needed. If you wrap the logger in your own
+    /// helper functions, mark them `#[track_caller]` too or they'll show up as the caller.
+    /// Default `false`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn caller_location(mut self, caller_location: bool) -> Self {
+        self.caller_location = caller_location;
+        self
+    }
+
+    /// Replaces the values of these keys in `data` and `context` with `"[REDACTED]"`, at any depth.
+    /// Keys are matched ignoring ASCII case, so `"authorization"` also covers `"Authorization"`.
+    /// Can be called more than once to add more keys
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn redact_keys(mut self, keys: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.redact_keys.extend(keys.into_iter().map(Into::into));
+        self
+    }
+
+    /// What redacted values are replaced with, see `RedactionStyle`. Default `RedactionStyle::Mask`
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn redaction_style(mut self, redaction_style: RedactionStyle) -> Self {
+        self.redaction_style = redaction_style;
+        self
+    }
+
+    /// Masks matches of `scrubber` in the message and every string in `data` and `context`.
+    /// Call once per scrubber, e.g. `.scrub(Scrubber::email()).scrub(Scrubber::credit_card().keep_last(4))`.
+    /// `Logger::scrubbed_count()` reports how many values were changed
+    #[cfg(feature = "scrub")]
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn scrub(mut self, scrubber: Scrubber) -> Self {
+        self.scrubbers.push(scrubber);
+        self
+    }
+
+    /// Caps how many bytes a log line can take up, not counting the newline.
+    /// Lines over it get `data` replaced with `"…(truncated 12345 bytes)"`, then the error, and if that's
+    /// not enough the message is cut short too. When the context alone is too big the line only keeps
+    /// the timestamp, level, message and `seq`. Default is no limit
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn max_line_bytes(mut self, max_line_bytes: usize) -> Self {
+        if max_line_bytes == 0 {
+            eprintln!("Provided 'max_line_bytes' is invalid, leaving it unlimited");
+        } else {
+            self.max_line_bytes = Some(max_line_bytes);
+        }
+        self
+    }
+
+    /// Cuts the message and strings in `data` and `context` longer than this many bytes, keeping
+    /// the start followed by `"…(truncated 123 bytes)"`. Default is no limit
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn max_string_len(mut self, max_string_len: usize) -> Self {
+        if max_string_len == 0 {
+            eprintln!("Provided 'max_string_len' is invalid, leaving it unlimited");
+        } else {
+            self.limits.string_len = Some(max_string_len);
+        }
+        self
+    }
+
+    /// Keeps the first this many elements of arrays in `data` and `context` and replaces the rest
+    /// with a single `"…(truncated 123 items)"` element. Default is no limit
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn max_array_len(mut self, max_array_len: usize) -> Self {
+        if max_array_len == 0 {
+            eprintln!("Provided 'max_array_len' is invalid, leaving it unlimited");
+        } else {
+            self.limits.array_len = Some(max_array_len);
+        }
+        self
+    }
+
+    /// How deep objects and arrays in `data` can nest, `data` itself is depth 1.
+    /// Anything deeper is replaced with `"…(truncated, max depth reached)"`. Default is no limit
+    #[must_use = "call `.init()` to create a Logger"]
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        if max_depth == 0 {
+            eprintln!("Provided 'max_depth' is invalid, leaving it unlimited");
+        } else {
+            self.limits.depth = Some(max_depth);
+        }
+        self
+    }
+
+    // Every top level key sjl adds on its own besides the reserved ones, along with the option that set it
+    fn top_level_keys(&self) -> Vec<(&'static str, &str)> {
+        let mut keys = vec![
+            ("timestamp_key", self.timestamp_key.as_ref()),
+            ("error_key", self.error_key.as_ref()),
+        ];
+        let optional_keys = [
+            (self.pid, "pid_key", &self.pid_key),
+            (self.hostname, "hostname_key", &self.hostname_key),
+            (self.thread_name, "thread_name_key", &self.thread_name_key),
+            (self.thread_id, "thread_id_key", &self.thread_id_key),
+            (self.sequence, "sequence_key", &self.sequence_key),
+        ];
+        for (enabled, option, key) in optional_keys {
+            if enabled {
+                keys.push((option, key.as_ref()));
+            }
+        }
+        if self.sequence {
+            keys.push(("sequence", LOGGER_INSTANCE_ID_KEY));
+        }
+        if self.caller_location {
+            keys.push(("caller_location", CALLER_KEY));
+        }
+        if self.max_line_bytes.is_some() || self.limits != Limits::default() {
+            keys.push(("size limits", TRUNCATED_KEY));
+        }
+        if self.sample_rules.iter().any(Option::is_some) {
+            keys.push(("sampling", SAMPLE_RATE_KEY));
+        }
+        if self.fingers_crossed.is_some() {
+            keys.push(("fingers_crossed", BACKFILL_KEY));
+        }
+        if self.collapse_repeats.is_some() {
+            keys.extend(
+                [REPEAT_COUNT_KEY, FIRST_TIMESTAMP_KEY, LAST_TIMESTAMP_KEY]
+                    .map(|key| ("collapse_repeats", key)),
+            );
+        }
+        // Any logger can get a span, so these are always taken
+        keys.extend(SPAN_KEYS.map(|key| ("spans", key)));
+        keys
+    }
+
     fn validate(&mut self) {
         assert!(
             self.buffer_pool_initial_capacity <= self.buffer_pool_max_capacity,
@@ -222,48 +691,128 @@ impl LoggerOptions {
             self.buffer_pool_max_capacity
         );
 
-        assert!(
-            !self.context.contains_key(self.timestamp_key),
-            "timestamp_key '{}' collides with a context key. Context keys show up at the top level with the timestamp, consider changing one of them",
-            self.timestamp_key
-        )
+        if let Some((_, trigger)) = self.fingers_crossed {
+            assert!(
+                trigger.severity() >= self.min_level.severity(),
+                "fingers_crossed trigger '{}' is below min_level '{}' so it would never be logged",
+                trigger.as_str(),
+                self.min_level.as_str()
+            );
+        }
+
+        let top_level_keys = self.top_level_keys();
+        for (i, (option, key)) in top_level_keys.iter().enumerate() {
+            assert!(!key.trim().is_empty(), "{option} '{key}' is empty.");
+            assert!(
+                !RESERVED_FIELD_NAMES.contains(key),
+                "{option} '{key}' is reserved. Reserved keys: {RESERVED_FIELD_NAMES:?}."
+            );
+            // Only events with an error or a span have these, they take the place of the context key on those
+            assert!(
+                matches!(*option, "error_key" | "spans") || !self.context.contains_key(*key),
+                "{option} '{key}' collides with a context key. Context keys show up at the top level with the timestamp, consider changing one of them"
+            );
+            if let Some((other_option, _)) = top_level_keys
+                .iter()
+                .skip(i + 1)
+                .find(|(_, other_key)| other_key == key)
+            {
+                panic!(
+                    "{option} and {other_option} are both set to '{key}', consider changing one of them"
+                );
+            }
+        }
     }
     // Initializes the logger and returns it
     #[must_use = "Logger must be kept to write logs. For example: logger.info()"]
     pub fn init(mut self) -> Logger {
         self.validate();
+        let timestamp_format =
+            TimestampFormat::parse(self.timestamp_format.as_deref(), self.timezone);
 
-        let (sender, worker) = crossbeam_channel::unbounded::<Vec<u8>>();
-
-        // Pre allocate a few buffers into the pool
-        let buffer_pool = Arc::new(ArrayQueue::new(self.buffer_pool_size));
-        for _ in 0..self.buffer_pool_size {
-            let _ = buffer_pool.push(Vec::with_capacity(self.buffer_pool_initial_capacity));
+        // These don't change for the life of the process so they can live with the rest of the context
+        if self.pid {
+            self.context
+                .insert(self.pid_key.to_string(), std::process::id().into());
+        }
+        if self.hostname {
+            self.context
+                .insert(self.hostname_key.to_string(), metadata::hostname().into());
+        }
+        if self.sequence {
+            self.context
+                .insert(LOGGER_INSTANCE_ID_KEY.to_string(), ids::random_id().into());
         }
 
-        // Run in background
-        let worker = Logger::handle_messages(
-            worker,
-            Arc::clone(&buffer_pool),
-            self.buffer_pool_max_capacity,
-            self.buffer_pool_initial_capacity,
-            self.flush_at_bytes,
-            self.flush_at_messages,
-            self.flush_interval,
+        let sanitizer = Sanitizer::new(
+            std::mem::take(&mut self.redact_keys),
+            self.redaction_style,
+            #[cfg(feature = "scrub")]
+            std::mem::take(&mut self.scrubbers),
+            self.limits,
         );
+        // Context never changes so it's sanitized once here instead of on every log
+        let context_truncated = Cell::new(false);
+        if let Some(sanitizer) = &sanitizer {
+            self.context = serde_json::to_value(Sanitized::new(
+                &self.context,
+                Some(sanitizer),
+                &context_truncated,
+            ))
+            .ok()
+            .and_then(|value| match value {
+                Value::Object(context) => Some(context),
+                _ => None,
+            })
+            .unwrap_or_default();
+        }
 
-        Logger {
+        // Reuse the shared writer if there is one, otherwise this logger gets its own thread
+        let writer = self.writer.take().unwrap_or_else(|| Writer::new(&self));
+        // Nothing to collapse on without the worker
+        let collapse_repeats = self.collapse_repeats.filter(|_| !writer.is_synchronous());
+
+        let inner = LoggerInner {
             min_level: self.min_level,
-            buffer_pool,
-            buffer_pool_initial_capacity: self.buffer_pool_initial_capacity,
-            timestamp_format: self.timestamp_format,
+            flush_on_level: self.flush_on_level,
+            flush_on_level_blocking: self.flush_on_level_blocking,
+            writer,
+            timestamp_format,
             timestamp_key: self.timestamp_key,
+            error_key: self.error_key,
+            timezone: self.timezone,
             pretty: self.pretty,
+            sequence: self.sequence.then(|| Sequencer::new(self.sequence_key)),
+            caller_location: self.caller_location,
+            thread_name_key: self.thread_name.then_some(self.thread_name_key),
+            thread_id_key: self.thread_id.then_some(self.thread_id_key),
             context: self.context,
-            sender: Some(sender),
-            worker: Some(worker),
+            sanitizer,
+            max_line_bytes: self.max_line_bytes,
+            context_truncated: context_truncated.get(),
+            sampler: Sampler::new(self.sample_rules),
+            rate_limiter: self
+                .rate_limit
+                .map(|(max_events, per)| RateLimiter::new(max_events, per, self.rate_limit_key)),
+            collapse_repeats,
+            backfill: self
+                .fingers_crossed
+                .map(|(capacity, trigger)| Backfill::new(capacity, trigger)),
+            span_events: self.span_events,
+        };
+        Logger {
+            inner: Arc::new(inner),
+            span: None,
         }
     }
+
+    /// Writes to a buffer the test can read back instead of stderr
+    #[cfg(test)]
+    pub(crate) fn captured(mut self) -> (Self, crate::writer::Captured) {
+        let captured = crate::writer::Captured::default();
+        self.sink = Sink::Captured(captured.clone());
+        (self, captured)
+    }
 }
 
 #[cfg(test)]
@@ -277,10 +826,14 @@ mod tests {
         assert_eq!(log_opts.min_level, LogLevel::Debug);
         assert_eq!(log_opts.timestamp_key, "timestamp");
         assert_eq!(log_opts.timestamp_format, None); // sets none
+        assert_eq!(log_opts.timezone, Timezone::Utc);
 
         assert_eq!(log_opts.flush_interval, Duration::from_secs(1));
         assert_eq!(log_opts.flush_at_bytes, 64 * 2048);
         assert_eq!(log_opts.flush_at_messages, 100);
+        assert_eq!(log_opts.flush_on_level, None);
+        assert_eq!(log_opts.flush_on_level_blocking, false);
+        assert_eq!(log_opts.synchronous, false);
 
         assert_eq!(log_opts.context.keys().len(), 0);
         assert_eq!(log_opts.buffer_pool_size, 10);
@@ -295,9 +848,12 @@ mod tests {
             .min_level(LogLevel::Error)
             .timestamp_format("%Y-%m")
             .timestamp_key("poop")
+            .timezone(Timezone::Local)
             .flush_interval(Duration::from_secs(69420))
             .flush_at_bytes(69420)
             .flush_at_messages(69)
+            .flush_on_level(LogLevel::Warn)
+            .flush_on_level_blocking(true)
             .context("69", "420")
             .buffer_pool_size(69420)
             .buffer_pool_initial_capacity(69420)
@@ -306,11 +862,14 @@ mod tests {
         assert_eq!(log_opts.pretty, true);
         assert_eq!(log_opts.min_level, LogLevel::Error);
         assert_eq!(log_opts.timestamp_key, "poop");
-        assert_eq!(log_opts.timestamp_format, Some("%Y-%m")); // sets none
+        assert_eq!(log_opts.timestamp_format.as_deref(), Some("%Y-%m"));
+        assert_eq!(log_opts.timezone, Timezone::Local);
 
         assert_eq!(log_opts.flush_interval, Duration::from_secs(69420));
         assert_eq!(log_opts.flush_at_bytes, 69420);
         assert_eq!(log_opts.flush_at_messages, 69);
+        assert_eq!(log_opts.flush_on_level, Some(LogLevel::Warn));
+        assert_eq!(log_opts.flush_on_level_blocking, true);
 
         assert_eq!(log_opts.context.keys().len(), 1);
         assert_eq!(log_opts.buffer_pool_size, 69420);
@@ -322,10 +881,154 @@ mod tests {
     fn test_init_happy_path() {
         let logger = LoggerOptions::default().init();
 
-        assert_eq!(logger.pretty, false);
-        assert_eq!(logger.min_level, LogLevel::Debug);
-        assert_eq!(logger.timestamp_key, "timestamp");
-        assert_eq!(logger.timestamp_format, None); // sets none
+        assert_eq!(logger.inner.pretty, false);
+        assert_eq!(logger.inner.min_level, LogLevel::Debug);
+        assert_eq!(logger.inner.timestamp_key, "timestamp");
+        // uses the default format
+        assert_eq!(logger.inner.timestamp_format, TimestampFormat::Default);
+    }
+
+    #[test]
+    fn test_process_metadata_is_off_by_default() {
+        let logger = LoggerOptions::default().init();
+
+        assert!(logger.inner.context.is_empty());
+        assert_eq!(logger.inner.thread_name_key, None);
+        assert_eq!(logger.inner.thread_id_key, None);
+    }
+
+    #[test]
+    fn test_adds_pid_and_hostname_to_context() {
+        let logger = LoggerOptions::default()
+            .pid(true)
+            .hostname(true)
+            .hostname_key("host")
+            .init();
+
+        assert_eq!(logger.inner.context["pid"], std::process::id());
+        assert_eq!(logger.inner.context["host"], metadata::hostname());
+        assert!(logger.inner.context.get("hostname").is_none());
+    }
+
+    #[test]
+    fn test_enables_thread_fields() {
+        let logger = LoggerOptions::default()
+            .thread_name(true)
+            .thread_id(true)
+            .thread_id_key("tid")
+            .init();
+
+        assert_eq!(logger.inner.thread_name_key.as_deref(), Some("thread"));
+        assert_eq!(logger.inner.thread_id_key.as_deref(), Some("tid"));
+    }
+
+    #[test]
+    fn test_error_key_can_share_a_context_key() {
+        let logger = LoggerOptions::default().context("error", "none").init();
+        assert_eq!(logger.inner.error_key, "error");
+
+        let logger = LoggerOptions::default().error_key("err").init();
+        assert_eq!(logger.inner.error_key, "err");
+    }
+
+    #[test]
+    #[should_panic(expected = "timestamp_key and error_key are both set to 'time'")]
+    fn test_error_key_cant_collide_with_other_keys() {
+        let _ = LoggerOptions::default()
+            .timestamp_key("time")
+            .error_key("time")
+            .init();
+    }
+
+    #[test]
+    #[should_panic(expected = "pid_key 'level' is reserved")]
+    fn test_metadata_key_cant_be_reserved() {
+        let _ = LoggerOptions::default().pid(true).pid_key("level").init();
+    }
+
+    #[test]
+    #[should_panic(expected = "thread_name_key 'service' collides with a context key")]
+    fn test_metadata_key_cant_collide_with_context() {
+        let _ = LoggerOptions::default()
+            .context("service", "payments")
+            .thread_name(true)
+            .thread_name_key("service")
+            .init();
+    }
+
+    #[test]
+    #[should_panic(expected = "timestamp_key and hostname_key are both set to 'time'")]
+    fn test_metadata_keys_cant_collide_with_each_other() {
+        let _ = LoggerOptions::default()
+            .timestamp_key("time")
+            .hostname(true)
+            .hostname_key("time")
+            .init();
+    }
+
+    #[test]
+    #[should_panic(expected = "hostname_key and spans are both set to 'span_id'")]
+    fn test_metadata_keys_cant_collide_with_span_keys() {
+        let _ = LoggerOptions::default()
+            .hostname(true)
+            .hostname_key("span_id")
+            .init();
+    }
+
+    #[test]
+    fn test_span_keys_can_share_a_context_key() {
+        let logger = LoggerOptions::default().context("trace_id", "mine").init();
+
+        assert_eq!(logger.inner.context["trace_id"], "mine");
+    }
+
+    #[test]
+    fn test_sequence_adds_instance_id() {
+        let a = LoggerOptions::default().sequence(true).init();
+        let b = LoggerOptions::default().sequence(true).init();
+
+        assert_eq!(
+            a.inner
+                .sequence
+                .as_ref()
+                .map(|sequencer| sequencer.key.as_ref()),
+            Some("seq")
+        );
+        let a_id = a.inner.context[LOGGER_INSTANCE_ID_KEY].as_str().unwrap();
+        let b_id = b.inner.context[LOGGER_INSTANCE_ID_KEY].as_str().unwrap();
+        assert_eq!(a_id.len(), 32);
+        assert_ne!(a_id, b_id);
+    }
+
+    #[test]
+    fn test_sequence_is_off_by_default() {
+        let logger = LoggerOptions::default().init();
+
+        assert!(logger.inner.sequence.is_none());
+        assert!(logger.inner.context.get(LOGGER_INSTANCE_ID_KEY).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "sequence 'logger_instance_id' collides with a context key")]
+    fn test_instance_id_cant_collide_with_context() {
+        let _ = LoggerOptions::default()
+            .context(LOGGER_INSTANCE_ID_KEY, "mine")
+            .sequence(true)
+            .init();
+    }
+
+    #[test]
+    #[should_panic(expected = "caller_location 'caller' collides with a context key")]
+    fn test_caller_cant_collide_with_context() {
+        let _ = LoggerOptions::default()
+            .context("caller", "me")
+            .caller_location(true)
+            .init();
+    }
+
+    #[test]
+    fn test_disabled_metadata_keys_are_not_validated() {
+        let _ = LoggerOptions::default().pid_key("level").init();
     }
 
     #[test]
@@ -333,8 +1036,8 @@ mod tests {
         let a = LoggerOptions::default().min_level(LogLevel::Debug).init();
         let b = LoggerOptions::default().min_level(LogLevel::Error).init();
 
-        assert_eq!(a.min_level, LogLevel::Debug);
-        assert_eq!(b.min_level, LogLevel::Error);
+        assert_eq!(a.inner.min_level, LogLevel::Debug);
+        assert_eq!(b.inner.min_level, LogLevel::Error);
     }
 
     #[test]
@@ -376,6 +1079,119 @@ mod tests {
         opts.validate();
     }
 
+    #[test]
+    #[should_panic(expected = "size limits 'truncated' collides with a context key")]
+    fn test_truncated_key_collision_with_context() {
+        let mut opts = LoggerOptions::default()
+            .context("truncated", false)
+            .max_depth(5);
+
+        opts.validate();
+    }
+
+    #[test]
+    fn test_ignores_zero_limits() {
+        let opts = LoggerOptions::default()
+            .max_line_bytes(0)
+            .max_string_len(0)
+            .max_array_len(0)
+            .max_depth(0);
+
+        assert_eq!(opts.max_line_bytes, None);
+        assert_eq!(opts.limits, Limits::default());
+    }
+
+    #[test]
+    fn test_sampling_rules_per_level() {
+        let opts = LoggerOptions::default()
+            .sample_rate(LogLevel::Debug, 0.01)
+            .sample_every_nth(LogLevel::Info, 10)
+            .sample_rate(LogLevel::Info, 0.5)
+            .sample_rate(LogLevel::Warn, 0.0)
+            .sample_every_nth(LogLevel::Error, 0);
+
+        assert_eq!(
+            opts.sample_rules,
+            [
+                Some(SampleRule::Rate(0.01)),
+                Some(SampleRule::Rate(0.5)),
+                None,
+                None
+            ]
+        );
+        assert!(opts.init().inner.sampler.is_some());
+        assert!(LoggerOptions::default().init().inner.sampler.is_none());
+    }
+
+    #[test]
+    fn test_ignores_invalid_rate_limit() {
+        let opts = LoggerOptions::default()
+            .rate_limit(0, Duration::from_secs(1))
+            .rate_limit(10, Duration::ZERO);
+
+        assert_eq!(opts.rate_limit, None);
+        assert!(opts.init().inner.rate_limiter.is_none());
+    }
+
+    #[test]
+    fn test_collapse_repeats() {
+        let opts = LoggerOptions::default().collapse_repeats(Duration::ZERO);
+        assert_eq!(opts.collapse_repeats, None);
+
+        let opts = opts.collapse_repeats(Duration::from_secs(5));
+        assert_eq!(opts.collapse_repeats, Some(Duration::from_secs(5)));
+        assert!(
+            opts.top_level_keys()
+                .contains(&("collapse_repeats", REPEAT_COUNT_KEY))
+        );
+
+        let logger = LoggerOptions::default()
+            .collapse_repeats(Duration::from_secs(5))
+            .synchronous(true)
+            .init();
+        assert!(logger.inner.collapse_repeats.is_none());
+    }
+
+    #[test]
+    fn test_ignores_empty_fingers_crossed_buffer() {
+        let opts = LoggerOptions::default().fingers_crossed(0, LogLevel::Error);
+
+        assert_eq!(opts.fingers_crossed, None);
+        assert!(opts.init().inner.backfill.is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "fingers_crossed trigger 'info' is below min_level 'warn'")]
+    fn test_fingers_crossed_trigger_must_be_logged() {
+        let _ = LoggerOptions::default()
+            .min_level(LogLevel::Warn)
+            .fingers_crossed(100, LogLevel::Info)
+            .init();
+    }
+
+    #[test]
+    fn test_accepts_owned_timestamp_format_and_key() {
+        let key = String::from("time");
+        let format = String::from("%Y-%m-%d");
+
+        let logger = LoggerOptions::default()
+            .timestamp_key(key)
+            .timestamp_format(format)
+            .init();
+
+        assert_eq!(logger.inner.timestamp_key, "time");
+        assert_eq!(
+            logger.inner.timestamp_format,
+            TimestampFormat::parse(Some("%Y-%m-%d"), Timezone::Utc)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a valid strftime format")]
+    fn test_invalid_timestamp_format_panics_on_init() {
+        let _ = LoggerOptions::default().timestamp_format("%Q").init();
+    }
+
     #[test]
     #[should_panic(expected = " is empty.")]
     fn test_no_empty_context_keys_after_normalization() {
@@ -425,4 +1241,34 @@ mod tests {
         let ops = LoggerOptions::default().buffer_pool_max_capacity(0);
         assert_eq!(ops.buffer_pool_max_capacity, 20 * 2048);
     }
+
+    #[test]
+    fn test_redact_keys_accumulate() {
+        let ops = LoggerOptions::default()
+            .redact_keys(["password"])
+            .redact_keys(vec![String::from("ssn")]);
+        assert_eq!(ops.redact_keys, ["password", "ssn"]);
+    }
+
+    #[test]
+    fn test_no_sanitizer_without_redact_keys() {
+        let logger = LoggerOptions::default().init();
+        assert!(logger.inner.sanitizer.is_none());
+    }
+
+    #[test]
+    fn test_redacts_context_at_init() {
+        let logger = LoggerOptions::default()
+            .context("service", "payments")
+            .context(
+                "db",
+                serde_json::json!({"host": "db1", "Password": "hunter2"}),
+            )
+            .redact_keys(["password"])
+            .init();
+
+        assert_eq!(logger.inner.context["service"], "payments");
+        assert_eq!(logger.inner.context["db"]["host"], "db1");
+        assert_eq!(logger.inner.context["db"]["Password"], "[REDACTED]");
+    }
 }
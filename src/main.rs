@@ -1,4 +1,4 @@
-use sjl::{LogLevel, LoggerOptions};
+use sjl::{LogLevel, LoggerOptions, Scrubber, Timezone};
 use std::time::Duration;
 
 fn main() {
@@ -19,6 +19,12 @@ fn main() {
         // ...or once this much time has passed since the last flush.
         // Whatever comes first wins.
         .flush_interval(Duration::from_millis(250))
+        // Write the batch right away when an Error comes in.
+        // Add .flush_on_level_blocking(true) to also wait for it to be written before `.error()` returns
+        .flush_on_level(LogLevel::Error)
+        // Write every log from the calling thread instead of batching on a background thread.
+        // Handy for CLIs and tests. The batching options above are ignored when this is on.
+        .synchronous(false)
         // Buffer pool
         // How many buffers to keep in the pool
         // Set this to around your expected concurrent in-flight log count
@@ -33,9 +39,48 @@ fn main() {
         .buffer_pool_max_capacity(100_000)
         // Rename the `timestamp` field in the output
         .timestamp_key("time")
+        // Rename the `error` field that `error_with()` adds
+        .error_key("error")
         // Custom chrono strftime format. Default is RFC 3339 with milliseconds.
         // Build your own from here: https://docs.rs/chrono/latest/chrono/format/strftime/index.html
         .timestamp_format("%FT%I:%M:%S%p")
+        // Render timestamps in UTC (default), the system's local time zone or a fixed offset.
+        // The default format ends with the offset instead of `Z` when it isn't UTC.
+        .timezone(Timezone::Utc)
+        // Process metadata. pid and hostname are looked up once, thread name/id per log line.
+        // Each field can be renamed with .pid_key(), .hostname_key(), .thread_name_key() and .thread_id_key()
+        .pid(true)
+        .hostname(true)
+        .thread_name(true)
+        .thread_id(true)
+        // Adds a `seq` number per logger and a random `logger_instance_id` so you can spot dropped lines
+        .sequence(true)
+        // Adds `"caller":{"file":..,"line":..}` pointing at the line that logged
+        .caller_location(true)
+        // Replace these keys' values in data and context with "[REDACTED]", at any depth.
+        // Use .redaction_style(RedactionStyle::Hash) for a stable hash instead
+        .redact_keys(["password", "authorization", "ssn"])
+        // Mask patterns in the message and any string in data, wherever they show up.
+        // Also: Scrubber::jwt(), ::bearer_token(), ::ipv4(), ::ipv6() and ::custom(regex)
+        .scrub(Scrubber::email())
+        .scrub(Scrubber::credit_card().keep_last(4))
+        // Size limits so one huge payload can't break ingestion. Anything cut off is replaced
+        // with a marker like "…(truncated 12345 bytes)" and the line gets "truncated": true
+        .max_line_bytes(256 * 1024)
+        .max_string_len(8 * 1024)
+        .max_array_len(100)
+        .max_depth(10)
+        // Keep 10% of Info and every 50th Debug event per message. Kept events get a `sample_rate` field
+        .sample_rate(LogLevel::Info, 0.1)
+        .sample_every_nth(LogLevel::Debug, 50)
+        // At most 10 events per second with the same message, the rest are reported in one summary event
+        .rate_limit(10, Duration::from_secs(1))
+        // Write runs of identical events once, then one event with `repeat_count` and first/last timestamps
+        .collapse_repeats(Duration::from_secs(5))
+        // Keep the last 100 events below min_level in memory, written with `"backfill": true` before an Error
+        .fingers_crossed(100, LogLevel::Error)
+        // Log "{name} entered" and "{name} exited" with `duration_ms` for every `logger.span(name)`
+        .span_events(LogLevel::Debug)
         // Pretty-print JSON using multiple lines. Default is compact, single line.
         .pretty(true)
         // Spawns a background worker thread and returns the logger. Only call this once or it'll panic.
@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub(crate) const DEFAULT_PID_KEY: &str = "pid";
+pub(crate) const DEFAULT_HOSTNAME_KEY: &str = "hostname";
+pub(crate) const DEFAULT_THREAD_NAME_KEY: &str = "thread";
+pub(crate) const DEFAULT_THREAD_ID_KEY: &str = "thread_id";
+pub(crate) const DEFAULT_SEQUENCE_KEY: &str = "seq";
+pub(crate) const LOGGER_INSTANCE_ID_KEY: &str = "logger_instance_id";
+
+// `ThreadId::as_u64` is still unstable so we hand out our own ids, once per thread
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static THREAD_ID: u64 = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// A small number that is unique per thread for the life of the process.
+/// Assigned the first time a thread logs, so it won't match the number in `ThreadId`'s Debug output
+pub(crate) fn current_thread_id() -> u64 {
+    THREAD_ID.with(|id| *id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_id_is_stable_per_thread() {
+        assert_eq!(current_thread_id(), current_thread_id());
+    }
+
+    #[test]
+    fn test_thread_id_is_unique_across_threads() {
+        let here = current_thread_id();
+        let there = std::thread::spawn(current_thread_id).join().unwrap();
+        assert_ne!(here, there);
+    }
+
+    #[test]
+    fn test_hostname_is_not_empty() {
+        assert!(!hostname().is_empty());
+    }
+}
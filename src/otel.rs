@@ -0,0 +1,51 @@
+use opentelemetry::{Context, trace::TraceContextExt};
+
+use crate::{
+    span::Span,
+    trace::{SpanId, TraceContext, TraceFlags, TraceId},
+};
+
+/// The span that's active in `opentelemetry::Context::current()`, `None` if there isn't a valid one
+pub(crate) fn active_span() -> Option<Span> {
+    Context::map_current(|context| {
+        let span_context = context.span().span_context().clone();
+        span_context.is_valid().then(|| {
+            Span::attached(&TraceContext {
+                trace_id: TraceId(u128::from_be_bytes(span_context.trace_id().to_bytes())),
+                parent_id: SpanId(u64::from_be_bytes(span_context.span_id().to_bytes())),
+                flags: TraceFlags(span_context.trace_flags().to_u8()),
+                tracestate: None,
+            })
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{self, SpanContext, TraceState};
+
+    #[test]
+    fn test_reads_the_active_span() {
+        assert!(active_span().is_none());
+
+        let span_context = SpanContext::new(
+            trace::TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            trace::SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            trace::TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let _guard = Context::current()
+            .with_remote_span_context(span_context)
+            .attach();
+
+        let span = active_span().unwrap();
+        assert_eq!(
+            span.trace_id.to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(span.id.to_string(), "00f067aa0ba902b7");
+        assert_eq!(span.flags, Some(TraceFlags::SAMPLED));
+    }
+}
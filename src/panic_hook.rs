@@ -0,0 +1,110 @@
+use serde::Serialize;
+use std::{backtrace::Backtrace, panic::PanicHookInfo};
+
+use crate::{Logger, log_level::LogLevel};
+
+#[derive(Serialize)]
+struct PanicLocation<'a> {
+    file: &'a str,
+    line: u32,
+    column: u32,
+}
+
+#[derive(Serialize)]
+struct PanicData<'a> {
+    location: Option<PanicLocation<'a>>,
+    thread: Option<&'a str>,
+    backtrace: String,
+}
+
+/// Logs panics as Error events on `logger` so they end up in the same JSON stream as everything else.
+///
+/// The event has the panic message, where it happened, the thread name and a backtrace in `data`.
+/// It never has `caller`, that would point at the hook rather than the panic.
+/// Waits for the logger to write everything out, then calls whatever panic hook was set before,
+/// so the default stderr message still shows up unless you replaced it.
+pub fn install_panic_hook(logger: &Logger) {
+    let logger = logger.clone();
+    let previous_hook = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |info: &PanicHookInfo| {
+        // Same fallback the default hook uses for payloads that aren't strings
+        let message = info.payload_as_str().unwrap_or("Box<dyn Any>");
+        let thread = std::thread::current();
+
+        let data = PanicData {
+            location: info.location().map(|location| PanicLocation {
+                file: location.file(),
+                line: location.line(),
+                column: location.column(),
+            }),
+            thread: thread.name(),
+            // Always captured since this only runs once per panic, `RUST_BACKTRACE` isn't needed
+            backtrace: Backtrace::force_capture().to_string(),
+        };
+
+        // The hook isn't where the panic happened, `data.location` is
+        logger.log_from(None, LogLevel::Error, message, None, data);
+        // The process might be about to exit, don't leave the panic sitting in a batch
+        logger.flush();
+
+        previous_hook(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LoggerOptions;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+
+    #[test]
+    fn test_logs_and_chains_to_previous_hook() {
+        // Other tests panic on their own threads while this runs, those still go to the original hook
+        let original: Arc<dyn Fn(&PanicHookInfo) + Send + Sync> =
+            Arc::from(std::panic::take_hook());
+        let test_thread = std::thread::current().id();
+        let previous_called = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&previous_called);
+        let fallback = Arc::clone(&original);
+        std::panic::set_hook(Box::new(move |info| {
+            if std::thread::current().id() == test_thread {
+                flag.store(true, Ordering::Relaxed);
+            } else {
+                fallback(info);
+            }
+        }));
+
+        let (options, captured) = LoggerOptions::default().caller_location(true).captured();
+        let logger = options.init();
+        install_panic_hook(&logger);
+
+        let line = line!() + 1;
+        let result = std::panic::catch_unwind(|| panic!("boom"));
+        // Put the hook from before the test back
+        drop(std::panic::take_hook());
+        std::panic::set_hook(Box::new(move |info| original(info)));
+
+        assert!(result.is_err());
+        assert!(previous_called.load(Ordering::Relaxed));
+
+        let lines = captured.lines();
+        let event = lines
+            .iter()
+            .find(|event| event["message"] == "boom")
+            .unwrap();
+        assert_eq!(event["level"], "error");
+        // Would point at the hook, the real location is in `data`
+        assert!(event.get("caller").is_none());
+        assert_eq!(event["data"]["location"]["file"], file!());
+        assert_eq!(event["data"]["location"]["line"], line);
+        assert_eq!(
+            event["data"]["thread"].as_str(),
+            std::thread::current().name()
+        );
+        assert!(event["data"]["backtrace"].is_string());
+    }
+}
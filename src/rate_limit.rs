@@ -0,0 +1,445 @@
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, VecDeque},
+    fmt::{self, Display},
+    hash::Hash,
+    panic::Location,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::log_level::LogLevel;
+
+// Buckets that are full again and have nothing left to report are dropped past this many keys.
+// While none of them can be, new keys aren't limited
+const MAX_TRACKED_KEYS: usize = 10_000;
+// How many of the oldest keys a new key looks at when the map is full, so it's never a full sweep
+const EVICTION_BATCH: usize = 32;
+
+/// What events are grouped by for `LoggerOptions::rate_limit()`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum RateLimitKey {
+    /// Events with the same message share a limit. This is the default.
+    #[default]
+    Message,
+    /// Events logged from the same line share a limit, whatever the message says
+    CallSite,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub(crate) enum Key {
+    Message(Arc<str>),
+    // `None` for events logged without one, like panics
+    CallSite(Option<&'static Location<'static>>),
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Message(message) => f.write_str(message),
+            Key::CallSite(Some(location)) => write!(f, "{location}"),
+            Key::CallSite(None) => f.write_str("unknown"),
+        }
+    }
+}
+
+struct Bucket {
+    key: Key,
+    tokens: f64,
+    refilled_at: Instant,
+    suppressed: u64,
+    // When the suppressed events get reported, `None` while nothing is suppressed
+    summary_due: Option<Instant>,
+    // From the last suppressed event, the summary is logged with them
+    level: LogLevel,
+    location: Option<&'static Location<'static>>,
+}
+
+/// `{"key": "...", "suppressed": 9876}` under `data` of a summary event
+#[derive(Serialize)]
+pub(crate) struct Summary {
+    pub(crate) key: String,
+    pub(crate) suppressed: u64,
+    #[serde(skip)]
+    pub(crate) level: LogLevel,
+    #[serde(skip)]
+    pub(crate) location: Option<&'static Location<'static>>,
+}
+
+impl Summary {
+    pub(crate) fn message(&self) -> String {
+        format!(
+            "suppressed {} similar events for key {}",
+            self.suppressed, self.key
+        )
+    }
+}
+
+pub(crate) enum Verdict {
+    Allow,
+    Suppress,
+    // The first suppressed event of a window, its summary is due at this point
+    SuppressAndSchedule(Key, Instant),
+}
+
+/// The buckets for one kind of key, oldest key first in `order`
+struct Buckets<K> {
+    by_key: HashMap<K, Bucket>,
+    order: VecDeque<K>,
+}
+
+impl<K: Clone + Eq + Hash> Buckets<K> {
+    fn new() -> Self {
+        Buckets {
+            by_key: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Looks `key` up as is, the owned key is only made when it's new.
+    /// `None` if it's new and there's no room for it
+    fn get_or_insert<Q>(
+        &mut self,
+        key: &Q,
+        insert: impl FnOnce() -> (K, Bucket),
+        idle: impl FnMut(&mut Bucket) -> bool,
+    ) -> Option<&mut Bucket>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if !self.by_key.contains_key(key) {
+            if self.by_key.len() >= MAX_TRACKED_KEYS {
+                self.evict(idle);
+            }
+            // Every key looked at is still busy, the new one goes unlimited rather than growing the map
+            if self.by_key.len() >= MAX_TRACKED_KEYS {
+                return None;
+            }
+            let (owned, bucket) = insert();
+            self.order.push_back(owned.clone());
+            self.by_key.insert(owned, bucket);
+        }
+        self.by_key.get_mut(key)
+    }
+
+    // Drops the idle ones among the oldest few keys, the rest go to the back of the line
+    fn evict(&mut self, mut idle: impl FnMut(&mut Bucket) -> bool) {
+        for _ in 0..EVICTION_BATCH.min(self.order.len()) {
+            let Some(key) = self.order.pop_front() else {
+                break;
+            };
+            if self
+                .by_key
+                .get_mut(&key)
+                .is_some_and(|bucket| !idle(bucket))
+            {
+                self.order.push_back(key);
+            } else {
+                self.by_key.remove(&key);
+            }
+        }
+    }
+}
+
+enum Tracked {
+    Message(Buckets<Arc<str>>),
+    CallSite(Buckets<Option<&'static Location<'static>>>),
+}
+
+/// A token bucket per key: up to `max_events` at once, refilled at `max_events` per `per`.
+pub(crate) struct RateLimiter {
+    max_events: f64,
+    per: Duration,
+    buckets: Mutex<Tracked>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(max_events: u32, per: Duration, key: RateLimitKey) -> Self {
+        RateLimiter {
+            max_events: f64::from(max_events),
+            per,
+            buckets: Mutex::new(match key {
+                RateLimitKey::Message => Tracked::Message(Buckets::new()),
+                RateLimitKey::CallSite => Tracked::CallSite(Buckets::new()),
+            }),
+        }
+    }
+
+    /// Also hands back any summary that's overdue for this key, which only happens
+    /// when nothing else is around to report it (synchronous mode)
+    pub(crate) fn check(
+        &self,
+        level: LogLevel,
+        message: &str,
+        location: Option<&'static Location<'static>>,
+    ) -> (Verdict, Option<Summary>) {
+        let now = Instant::now();
+        let new_bucket = |key| Bucket {
+            key,
+            tokens: self.max_events,
+            refilled_at: now,
+            suppressed: 0,
+            summary_due: None,
+            level,
+            location,
+        };
+        let idle = |bucket: &mut Bucket| {
+            bucket.summary_due.is_none() && self.refill(bucket, now) >= self.max_events
+        };
+        // A panic while holding the lock can't leave a bucket half updated
+        match &mut *self.buckets.lock().unwrap_or_else(PoisonError::into_inner) {
+            Tracked::Message(buckets) => buckets.get_or_insert(
+                message,
+                || {
+                    let key = Arc::<str>::from(message);
+                    (Arc::clone(&key), new_bucket(Key::Message(key)))
+                },
+                idle,
+            ),
+            Tracked::CallSite(buckets) => buckets.get_or_insert(
+                &location,
+                || (location, new_bucket(Key::CallSite(location))),
+                idle,
+            ),
+        }
+        .map_or((Verdict::Allow, None), |bucket| {
+            self.check_bucket(bucket, now, level, location)
+        })
+    }
+
+    fn check_bucket(
+        &self,
+        bucket: &mut Bucket,
+        now: Instant,
+        level: LogLevel,
+        location: Option<&'static Location<'static>>,
+    ) -> (Verdict, Option<Summary>) {
+        let overdue = bucket
+            .summary_due
+            .is_some_and(|due| due <= now)
+            .then(|| Self::take(bucket))
+            .flatten();
+
+        if self.refill(bucket, now) >= 1.0 {
+            bucket.tokens -= 1.0;
+            return (Verdict::Allow, overdue);
+        }
+
+        bucket.suppressed += 1;
+        bucket.level = level;
+        bucket.location = location;
+        if bucket.summary_due.is_some() {
+            return (Verdict::Suppress, overdue);
+        }
+        let due = now + self.per;
+        bucket.summary_due = Some(due);
+        (
+            Verdict::SuppressAndSchedule(bucket.key.clone(), due),
+            overdue,
+        )
+    }
+
+    /// What was suppressed for `key` since the last summary, `None` if nothing was
+    pub(crate) fn take_summary(&self, key: &Key) -> Option<Summary> {
+        match (
+            &mut *self.buckets.lock().unwrap_or_else(PoisonError::into_inner),
+            key,
+        ) {
+            (Tracked::Message(buckets), Key::Message(message)) => {
+                buckets.by_key.get_mut(message.as_ref())
+            }
+            (Tracked::CallSite(buckets), Key::CallSite(location)) => {
+                buckets.by_key.get_mut(location)
+            }
+            _ => None,
+        }
+        .and_then(Self::take)
+    }
+
+    /// Everything still waiting to be reported, for when the logger goes away
+    pub(crate) fn take_all_summaries(&self) -> Vec<Summary> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        match &mut *buckets {
+            Tracked::Message(buckets) => {
+                buckets.by_key.values_mut().filter_map(Self::take).collect()
+            }
+            Tracked::CallSite(buckets) => {
+                buckets.by_key.values_mut().filter_map(Self::take).collect()
+            }
+        }
+    }
+
+    fn take(bucket: &mut Bucket) -> Option<Summary> {
+        bucket.summary_due = None;
+        let suppressed = std::mem::take(&mut bucket.suppressed);
+        (suppressed > 0).then(|| Summary {
+            key: bucket.key.to_string(),
+            suppressed,
+            level: bucket.level,
+            location: bucket.location,
+        })
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = self.max_events.min(
+            elapsed
+                .div_duration_f64(self.per)
+                .mul_add(self.max_events, bucket.tokens),
+        );
+        bucket.refilled_at = now;
+        bucket.tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(limiter: &RateLimiter, message: &str, events: usize) -> usize {
+        (0..events)
+            .filter(|_| {
+                matches!(
+                    limiter
+                        .check(LogLevel::Error, message, Some(Location::caller()))
+                        .0,
+                    Verdict::Allow
+                )
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_allows_a_burst_then_suppresses() {
+        let limiter = RateLimiter::new(10, Duration::from_mins(1), RateLimitKey::Message);
+
+        assert_eq!(allowed(&limiter, "retry failed", 1_000), 10);
+        // Other messages have their own bucket
+        assert_eq!(allowed(&limiter, "other", 5), 5);
+    }
+
+    #[test]
+    fn test_schedules_one_summary_per_window() {
+        let limiter = RateLimiter::new(1, Duration::from_mins(1), RateLimitKey::Message);
+        let check = || {
+            limiter
+                .check(LogLevel::Warn, "retry", Some(Location::caller()))
+                .0
+        };
+
+        assert!(matches!(check(), Verdict::Allow));
+        assert!(matches!(check(), Verdict::SuppressAndSchedule(..)));
+        assert!(matches!(check(), Verdict::Suppress));
+
+        let summary = limiter.take_summary(&Key::Message("retry".into())).unwrap();
+        assert_eq!(summary.suppressed, 2);
+        assert_eq!(summary.level, LogLevel::Warn);
+        assert_eq!(
+            summary.message(),
+            "suppressed 2 similar events for key retry"
+        );
+
+        // The next suppressed event starts a new window
+        assert!(matches!(check(), Verdict::SuppressAndSchedule(..)));
+    }
+
+    #[test]
+    fn test_refills_over_time() {
+        let limiter = RateLimiter::new(2, Duration::from_millis(20), RateLimitKey::Message);
+
+        assert_eq!(allowed(&limiter, "tick", 10), 2);
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(allowed(&limiter, "tick", 10), 2);
+    }
+
+    #[test]
+    fn test_overdue_summary_is_handed_back() {
+        let limiter = RateLimiter::new(1, Duration::from_millis(10), RateLimitKey::Message);
+        let _ = allowed(&limiter, "tick", 5);
+
+        std::thread::sleep(Duration::from_millis(20));
+        let (_, overdue) = limiter.check(LogLevel::Info, "tick", Some(Location::caller()));
+
+        assert_eq!(overdue.unwrap().suppressed, 4);
+    }
+
+    #[test]
+    fn test_call_site_key_ignores_message() {
+        let limiter = RateLimiter::new(3, Duration::from_mins(1), RateLimitKey::CallSite);
+        let location = Location::caller();
+
+        let allowed = (0..10)
+            .filter(|i| {
+                matches!(
+                    limiter
+                        .check(LogLevel::Info, &format!("user {i} failed"), Some(location))
+                        .0,
+                    Verdict::Allow
+                )
+            })
+            .count();
+
+        assert_eq!(allowed, 3);
+        assert_eq!(
+            limiter.take_all_summaries().first().unwrap().key,
+            location.to_string()
+        );
+    }
+
+    fn tracked(limiter: &RateLimiter) -> usize {
+        let (by_key, order) = match &*limiter.buckets.lock().unwrap() {
+            Tracked::Message(buckets) => (buckets.by_key.len(), buckets.order.len()),
+            Tracked::CallSite(buckets) => (buckets.by_key.len(), buckets.order.len()),
+        };
+        assert_eq!(by_key, order);
+        by_key
+    }
+
+    fn fill(limiter: &RateLimiter) {
+        // Still has a summary to report, so it's never dropped
+        let _ = allowed(limiter, "busy", 2);
+        for i in 1..MAX_TRACKED_KEYS {
+            let _ = allowed(limiter, &i.to_string(), 1);
+        }
+    }
+
+    #[test]
+    fn test_keys_past_the_limit_are_not_tracked() {
+        let limiter = RateLimiter::new(1, Duration::from_mins(1), RateLimitKey::Message);
+        fill(&limiter);
+
+        // Spent buckets aren't idle, so there's nothing to evict and the new key isn't limited
+        assert_eq!(allowed(&limiter, "new", 5), 5);
+        for i in 0..100 {
+            let _ = allowed(&limiter, &format!("new {i}"), 1);
+        }
+
+        assert_eq!(tracked(&limiter), MAX_TRACKED_KEYS);
+    }
+
+    #[test]
+    fn test_evicts_a_batch_of_full_buckets() {
+        let limiter = RateLimiter::new(1, Duration::from_millis(1), RateLimitKey::Message);
+        fill(&limiter);
+        std::thread::sleep(Duration::from_millis(10));
+
+        let _ = allowed(&limiter, "new", 1);
+
+        // "busy" is the oldest key but is kept, the next ones in line go
+        assert_eq!(
+            tracked(&limiter),
+            MAX_TRACKED_KEYS + 1 - (EVICTION_BATCH - 1)
+        );
+        let busy = limiter.take_summary(&Key::Message("busy".into())).unwrap();
+        assert_eq!(busy.suppressed, 1);
+        // Up to the next batch, new keys don't evict anything
+        let _ = allowed(&limiter, "newer", 1);
+        assert_eq!(
+            tracked(&limiter),
+            MAX_TRACKED_KEYS + 2 - (EVICTION_BATCH - 1)
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{ids, log_level::LogLevel};
+
+pub(crate) const SAMPLE_RATE_KEY: &str = "sample_rate";
+
+// Messages with ids or timestamps baked in would grow the every-Nth counters forever,
+// so they start over once there are this many
+const MAX_COUNTED_MESSAGES: usize = 10_000;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub(crate) enum SampleRule {
+    // Keep each event with this probability, in (0, 1]
+    Rate(f64),
+    // Keep the 1st, (N+1)th, (2N+1)th... event of each message
+    EveryNth(u64),
+}
+
+impl SampleRule {
+    fn rate(self) -> f64 {
+        match self {
+            SampleRule::Rate(rate) => rate,
+            #[allow(clippy::cast_precision_loss)] // fine for a ratio
+            SampleRule::EveryNth(n) => 1.0 / n as f64,
+        }
+    }
+}
+
+pub(crate) enum Decision {
+    Keep,
+    // Kept, the event carries `sample_rate` so backends can re-weight counts
+    Sampled(f64),
+    Drop,
+}
+
+struct LevelSampler {
+    rule: SampleRule,
+    // For `Rate`, events with a random u64 below this are kept
+    threshold: u64,
+    // For `EveryNth`, how many times each message has been seen
+    seen: Mutex<HashMap<Box<str>, u64>>,
+}
+
+impl LevelSampler {
+    fn new(rule: SampleRule) -> Self {
+        // 2^64, so a rate of 1.0 keeps everything
+        const RANGE: f64 = 18_446_744_073_709_551_616.0;
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // saturates at u64::MAX
+        let threshold = match rule {
+            SampleRule::Rate(rate) => (rate * RANGE) as u64,
+            SampleRule::EveryNth(_) => 0,
+        };
+        LevelSampler {
+            rule,
+            threshold,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn keep(&self, message: &str) -> bool {
+        match self.rule {
+            SampleRule::Rate(rate) => rate >= 1.0 || ids::random_u64() < self.threshold,
+            SampleRule::EveryNth(n) => {
+                // A panic while holding the lock only ever leaves a count behind, nothing to clean up
+                let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+                if let Some(count) = seen.get_mut(message) {
+                    *count += 1;
+                    return *count % n == 0;
+                }
+                if seen.len() >= MAX_COUNTED_MESSAGES {
+                    seen.clear();
+                }
+                seen.insert(message.into(), 0);
+                true
+            }
+        }
+    }
+}
+
+/// Decides which events to keep before anything is serialized, one rule per level.
+pub(crate) struct Sampler {
+    // Indexed by `LogLevel::severity()`
+    rules: [Option<LevelSampler>; 4],
+}
+
+impl Sampler {
+    /// `None` when no level is sampled
+    pub(crate) fn new(rules: [Option<SampleRule>; 4]) -> Option<Self> {
+        rules.iter().any(Option::is_some).then(|| Sampler {
+            rules: rules.map(|rule| rule.map(LevelSampler::new)),
+        })
+    }
+
+    pub(crate) fn decide(&self, level: LogLevel, message: &str) -> Decision {
+        match self.rules.get(level.severity()).and_then(Option::as_ref) {
+            None => Decision::Keep,
+            Some(rule) if rule.keep(message) => Decision::Sampled(rule.rule.rate()),
+            Some(_) => Decision::Drop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampler(level: LogLevel, rule: SampleRule) -> Sampler {
+        let mut rules = [None; 4];
+        if let Some(slot) = rules.get_mut(level.severity()) {
+            *slot = Some(rule);
+        }
+        Sampler::new(rules).unwrap()
+    }
+
+    fn kept(sampler: &Sampler, level: LogLevel, message: &str, events: usize) -> usize {
+        (0..events)
+            .filter(|_| !matches!(sampler.decide(level, message), Decision::Drop))
+            .count()
+    }
+
+    #[test]
+    fn test_no_rules_means_no_sampler() {
+        assert!(Sampler::new([None; 4]).is_none());
+    }
+
+    #[test]
+    fn test_unsampled_levels_are_kept_without_rate() {
+        let sampler = sampler(LogLevel::Debug, SampleRule::Rate(0.01));
+
+        assert!(matches!(
+            sampler.decide(LogLevel::Warn, "disk full"),
+            Decision::Keep
+        ));
+    }
+
+    #[test]
+    fn test_rate_keeps_roughly_that_share() {
+        let sampler = sampler(LogLevel::Info, SampleRule::Rate(0.1));
+
+        let kept = kept(&sampler, LogLevel::Info, "request", 20_000);
+
+        assert!((1_500..2_500).contains(&kept), "kept {kept}");
+    }
+
+    #[test]
+    fn test_rate_of_one_keeps_everything() {
+        let sampler = sampler(LogLevel::Info, SampleRule::Rate(1.0));
+
+        assert_eq!(kept(&sampler, LogLevel::Info, "request", 1_000), 1_000);
+    }
+
+    #[test]
+    fn test_every_nth_counts_per_message() {
+        let sampler = sampler(LogLevel::Debug, SampleRule::EveryNth(50));
+
+        assert_eq!(kept(&sampler, LogLevel::Debug, "cache miss", 100), 2);
+        assert_eq!(kept(&sampler, LogLevel::Debug, "cache hit", 10), 1);
+        // The first one of each message is always kept
+        assert!(matches!(
+            sampler.decide(LogLevel::Debug, "new message"),
+            Decision::Sampled(rate) if (rate - 0.02).abs() < f64::EPSILON
+        ));
+    }
+}
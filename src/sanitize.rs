@@ -0,0 +1,1127 @@
+use serde::{
+    Serialize, Serializer,
+    ser::{
+        self, Impossible, SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant,
+        SerializeTuple, SerializeTupleStruct, SerializeTupleVariant,
+    },
+};
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    fmt::{self, Display},
+    io,
+};
+
+#[cfg(feature = "scrub")]
+use crate::scrub::{Scrubber, Scrubbers};
+
+pub const REDACTED: &str = "[REDACTED]";
+
+/// What a redacted value is replaced with.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum RedactionStyle {
+    /// `"[REDACTED]"`. This is the default.
+    #[default]
+    Mask,
+    /// `"hash:<16 hex chars>"`, a 64-bit FNV-1a hash of the value's JSON.
+    /// The same value always hashes the same so you can still correlate lines,
+    /// but it's not a cryptographic hash, short values like PINs can be guessed
+    Hash,
+}
+
+/// Caps on what gets serialized, `None` means no limit
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub(crate) struct Limits {
+    /// In bytes, cut on a char boundary
+    pub(crate) string_len: Option<usize>,
+    pub(crate) array_len: Option<usize>,
+    /// The top level of `data` is depth 1
+    pub(crate) depth: Option<usize>,
+}
+
+impl Limits {
+    fn is_unlimited(&self) -> bool {
+        *self == Limits::default()
+    }
+}
+
+/// Replaces whatever was cut off
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum Marker {
+    Bytes(usize),
+    Items(usize),
+    MaxDepth,
+}
+
+impl Marker {
+    /// For `value` as a whole, when it's dropped
+    pub(crate) fn size_of<T: Serialize + ?Sized>(value: &T) -> Self {
+        let mut bytes = ByteCounter::default();
+        let _ = serde_json::to_writer(&mut bytes, value);
+        Marker::Bytes(bytes.0)
+    }
+}
+
+impl Display for Marker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Marker::Bytes(bytes) => write!(f, "…(truncated {bytes} bytes)"),
+            Marker::Items(items) => write!(f, "…(truncated {items} items)"),
+            Marker::MaxDepth => write!(f, "…(truncated, max depth reached)"),
+        }
+    }
+}
+
+impl Serialize for Marker {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Cuts `value` down to at most `max_bytes` (on a char boundary) followed by a `Marker::Bytes`.
+/// `None` if it already fits
+pub(crate) fn truncate_str(value: &str, max_bytes: usize) -> Option<String> {
+    if value.len() <= max_bytes {
+        return None;
+    }
+    let end = (0..=max_bytes)
+        .rev()
+        .find(|end| value.is_char_boundary(*end))
+        .unwrap_or(0);
+    Some(format!(
+        "{}{}",
+        value.get(..end).unwrap_or_default(),
+        Marker::Bytes(value.len() - end)
+    ))
+}
+
+/// Counts the bytes of JSON written to it
+#[derive(Default)]
+pub(crate) struct ByteCounter(pub(crate) usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Rules applied to values while they're serialized, without building a `serde_json::Value` first.
+pub(crate) struct Sanitizer {
+    pub(crate) redact_keys: Vec<String>,
+    pub(crate) redaction_style: RedactionStyle,
+    #[cfg(feature = "scrub")]
+    pub(crate) scrubbers: Option<Scrubbers>,
+    pub(crate) limits: Limits,
+}
+
+impl Sanitizer {
+    /// `None` when there are no rules so logging skips the wrapper entirely
+    pub(crate) fn new(
+        redact_keys: Vec<String>,
+        redaction_style: RedactionStyle,
+        #[cfg(feature = "scrub")] scrubbers: Vec<Scrubber>,
+        limits: Limits,
+    ) -> Option<Self> {
+        #[cfg(feature = "scrub")]
+        let scrubbers = (!scrubbers.is_empty()).then(|| Scrubbers::new(scrubbers));
+
+        let sanitizer = Sanitizer {
+            redact_keys,
+            redaction_style,
+            #[cfg(feature = "scrub")]
+            scrubbers,
+            limits,
+        };
+        (!sanitizer.redact_keys.is_empty()
+            || sanitizer.scrubs_strings()
+            || !sanitizer.limits.is_unlimited())
+        .then_some(sanitizer)
+    }
+
+    /// Masks anything the scrubbers match, borrowed when there was nothing to mask
+    #[cfg_attr(not(feature = "scrub"), allow(clippy::unused_self))]
+    pub(crate) fn scrub<'s>(&self, value: &'s str) -> Cow<'s, str> {
+        #[cfg(feature = "scrub")]
+        if let Some(scrubbers) = &self.scrubbers {
+            return scrubbers.scrub(value);
+        }
+        Cow::Borrowed(value)
+    }
+
+    /// Scrubs and then truncates a string, setting `truncated` if it had to be cut
+    pub(crate) fn sanitize_str<'s>(&self, value: &'s str, truncated: &Cell<bool>) -> Cow<'s, str> {
+        let scrubbed = self.scrub(value);
+        self.limits
+            .string_len
+            .and_then(|max_string_len| truncate_str(&scrubbed, max_string_len))
+            .map_or(scrubbed, |shortened| {
+                truncated.set(true);
+                Cow::Owned(shortened)
+            })
+    }
+
+    #[cfg_attr(not(feature = "scrub"), allow(clippy::unused_self))]
+    fn scrubs_strings(&self) -> bool {
+        #[cfg(feature = "scrub")]
+        return self.scrubbers.is_some();
+        #[cfg(not(feature = "scrub"))]
+        false
+    }
+
+    // Whether strings have to be looked at, not just passed through
+    fn inspects_strings(&self) -> bool {
+        self.scrubs_strings() || self.limits.string_len.is_some()
+    }
+
+    fn should_redact(&self, key: &str) -> bool {
+        self.redact_keys
+            .iter()
+            .any(|redact_key| redact_key.eq_ignore_ascii_case(key))
+    }
+}
+
+// Everything the serializer needs while walking one value
+#[derive(Copy, Clone)]
+struct Ctx<'a> {
+    sanitizer: &'a Sanitizer,
+    // Set when anything was cut off so the event can say so
+    truncated: &'a Cell<bool>,
+    depth: usize,
+}
+
+impl<'a> Ctx<'a> {
+    fn wrap<'b, T: ?Sized>(self, value: &'b T) -> Sanitized<'b, T>
+    where
+        'a: 'b,
+    {
+        Sanitized {
+            value,
+            ctx: Some(self),
+        }
+    }
+
+    fn nested(self) -> Self {
+        Ctx {
+            depth: self.depth + 1,
+            ..self
+        }
+    }
+
+    fn is_too_deep(&self) -> bool {
+        self.sanitizer
+            .limits
+            .depth
+            .is_some_and(|max_depth| self.depth > max_depth)
+    }
+
+    fn redacted<T: ?Sized>(self, value: &T) -> Redacted<'_, T> {
+        Redacted {
+            value,
+            style: self.sanitizer.redaction_style,
+        }
+    }
+}
+
+/// Serializes `value` through the sanitizer, or as is when there isn't one.
+pub(crate) struct Sanitized<'a, T: ?Sized> {
+    value: &'a T,
+    ctx: Option<Ctx<'a>>,
+}
+
+impl<'a, T: ?Sized> Sanitized<'a, T> {
+    /// `truncated` is set if any limit cut something off
+    pub(crate) fn new(
+        value: &'a T,
+        sanitizer: Option<&'a Sanitizer>,
+        truncated: &'a Cell<bool>,
+    ) -> Self {
+        Sanitized {
+            value,
+            ctx: sanitizer.map(|sanitizer| Ctx {
+                sanitizer,
+                truncated,
+                depth: 0,
+            }),
+        }
+    }
+}
+
+impl<T: Serialize + ?Sized> Serialize for Sanitized<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.ctx {
+            Some(ctx) => self.value.serialize(SanitizingSerializer {
+                inner: serializer,
+                ctx,
+            }),
+            None => self.value.serialize(serializer),
+        }
+    }
+}
+
+// Replaces a value under a redacted key
+struct Redacted<'a, T: ?Sized> {
+    value: &'a T,
+    style: RedactionStyle,
+}
+
+impl<T: Serialize + ?Sized> Serialize for Redacted<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.style {
+            RedactionStyle::Mask => serializer.serialize_str(REDACTED),
+            RedactionStyle::Hash => Hashed(self.value).serialize(serializer),
+        }
+    }
+}
+
+/// `"hash:<16 hex chars>"` for the value, see `RedactionStyle::Hash`
+pub struct Hashed<'a, T: ?Sized>(pub &'a T);
+
+impl<T: Serialize + ?Sized> Serialize for Hashed<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut hasher = Fnv1a::default();
+        serde_json::to_writer(&mut hasher, self.0).map_err(ser::Error::custom)?;
+        serializer.collect_str(&format_args!("hash:{:016x}", hasher.0))
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// Hashes the JSON as it's written so nothing is buffered
+pub(crate) struct Fnv1a(pub(crate) u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(FNV_OFFSET_BASIS)
+    }
+}
+
+impl io::Write for Fnv1a {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(FNV_PRIME);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct SanitizingSerializer<'a, S> {
+    inner: S,
+    ctx: Ctx<'a>,
+}
+
+impl<'a, S: Serializer> SanitizingSerializer<'a, S> {
+    // Containers past `max_depth` are replaced with a marker instead of being started
+    fn compound<C>(
+        self,
+        start: impl FnOnce(S) -> Result<C, S::Error>,
+    ) -> Result<Compound<'a, C, S>, S::Error> {
+        let ctx = self.ctx.nested();
+        let state = if ctx.is_too_deep() {
+            ctx.truncated.set(true);
+            State::Elided(self.inner)
+        } else {
+            State::Forward(start(self.inner)?)
+        };
+        Ok(Compound {
+            state,
+            ctx,
+            redact_next_value: false,
+            elements: 0,
+        })
+    }
+}
+
+enum State<C, S> {
+    Forward(C),
+    // Too deep, nothing inside is written and the whole thing becomes a marker
+    Elided(S),
+}
+
+// Wraps every compound serializer so the values inside get sanitized too
+struct Compound<'a, C, S> {
+    state: State<C, S>,
+    ctx: Ctx<'a>,
+    // Set by `serialize_key` when the value that follows has to be redacted
+    redact_next_value: bool,
+    elements: usize,
+}
+
+impl<C, S: Serializer> Compound<'_, C, S> {
+    fn end_elided(serializer: S) -> Result<S::Ok, S::Error> {
+        Marker::MaxDepth.serialize(serializer)
+    }
+
+    // Counts an array element, `false` once past `max_array_len`
+    fn keep_element(&mut self) -> bool {
+        self.elements += 1;
+        self.ctx
+            .sanitizer
+            .limits
+            .array_len
+            .is_none_or(|max_array_len| self.elements <= max_array_len)
+    }
+
+    fn dropped_elements(&self) -> Option<usize> {
+        let max_array_len = self.ctx.sanitizer.limits.array_len?;
+        let dropped = self.elements.saturating_sub(max_array_len);
+        (dropped > 0).then(|| {
+            self.ctx.truncated.set(true);
+            dropped
+        })
+    }
+}
+
+impl<'a, S: Serializer> Serializer for SanitizingSerializer<'a, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeSeq = Compound<'a, S::SerializeSeq, S>;
+    type SerializeTuple = Compound<'a, S::SerializeTuple, S>;
+    type SerializeTupleStruct = Compound<'a, S::SerializeTupleStruct, S>;
+    type SerializeTupleVariant = Compound<'a, S::SerializeTupleVariant, S>;
+    type SerializeMap = Compound<'a, S::SerializeMap, S>;
+    type SerializeStruct = Compound<'a, S::SerializeStruct, S>;
+    type SerializeStructVariant = Compound<'a, S::SerializeStructVariant, S>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_bool(v)
+    }
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_i8(v)
+    }
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_i16(v)
+    }
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_i32(v)
+    }
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_i64(v)
+    }
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_i128(v)
+    }
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_u8(v)
+    }
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_u16(v)
+    }
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_u32(v)
+    }
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_u64(v)
+    }
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_u128(v)
+    }
+    fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_f32(v)
+    }
+    fn serialize_f64(self, v: f64) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_f64(v)
+    }
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_char(v)
+    }
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
+        self.inner
+            .serialize_str(&self.ctx.sanitizer.sanitize_str(v, self.ctx.truncated))
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_bytes(v)
+    }
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_none()
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_some(&self.ctx.wrap(value))
+    }
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_unit()
+    }
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Self::Ok, Self::Error> {
+        self.inner.serialize_unit_struct(name)
+    }
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.inner
+            .serialize_unit_variant(name, variant_index, variant)
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.inner
+            .serialize_newtype_struct(name, &self.ctx.wrap(value))
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.inner
+            .serialize_newtype_variant(name, variant_index, variant, &self.ctx.wrap(value))
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        // The length changes if elements get dropped
+        let len = len.map(|len| {
+            self.ctx
+                .sanitizer
+                .limits
+                .array_len
+                .map_or(len, |max_array_len| len.min(max_array_len + 1))
+        });
+        self.compound(|inner| inner.serialize_seq(len))
+    }
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        self.compound(|inner| inner.serialize_tuple(len))
+    }
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        self.compound(|inner| inner.serialize_tuple_struct(name, len))
+    }
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        self.compound(|inner| inner.serialize_tuple_variant(name, variant_index, variant, len))
+    }
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        self.compound(|inner| inner.serialize_map(len))
+    }
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        self.compound(|inner| inner.serialize_struct(name, len))
+    }
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        self.compound(|inner| inner.serialize_struct_variant(name, variant_index, variant, len))
+    }
+    // Values like `IpAddr` only get rendered to a `String` when strings have to be inspected
+    fn collect_str<T: Display + ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        if self.ctx.sanitizer.inspects_strings() {
+            self.serialize_str(&value.to_string())
+        } else {
+            self.inner.collect_str(value)
+        }
+    }
+    fn is_human_readable(&self) -> bool {
+        self.inner.is_human_readable()
+    }
+}
+
+impl<C, S> SerializeSeq for Compound<'_, C, S>
+where
+    C: SerializeSeq,
+    S: Serializer<Ok = C::Ok, Error = C::Error>,
+{
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        if !self.keep_element() {
+            return Ok(());
+        }
+        match &mut self.state {
+            State::Forward(inner) => inner.serialize_element(&self.ctx.wrap(value)),
+            State::Elided(_) => Ok(()),
+        }
+    }
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        let dropped = self.dropped_elements();
+        match self.state {
+            State::Forward(mut inner) => {
+                if let Some(dropped) = dropped {
+                    inner.serialize_element(&Marker::Items(dropped))?;
+                }
+                inner.end()
+            }
+            State::Elided(serializer) => Self::end_elided(serializer),
+        }
+    }
+}
+
+impl<C, S> SerializeTuple for Compound<'_, C, S>
+where
+    C: SerializeTuple,
+    S: Serializer<Ok = C::Ok, Error = C::Error>,
+{
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        match &mut self.state {
+            State::Forward(inner) => inner.serialize_element(&self.ctx.wrap(value)),
+            State::Elided(_) => Ok(()),
+        }
+    }
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        match self.state {
+            State::Forward(inner) => inner.end(),
+            State::Elided(serializer) => Self::end_elided(serializer),
+        }
+    }
+}
+
+impl<C, S> SerializeTupleStruct for Compound<'_, C, S>
+where
+    C: SerializeTupleStruct,
+    S: Serializer<Ok = C::Ok, Error = C::Error>,
+{
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        match &mut self.state {
+            State::Forward(inner) => inner.serialize_field(&self.ctx.wrap(value)),
+            State::Elided(_) => Ok(()),
+        }
+    }
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        match self.state {
+            State::Forward(inner) => inner.end(),
+            State::Elided(serializer) => Self::end_elided(serializer),
+        }
+    }
+}
+
+impl<C, S> SerializeTupleVariant for Compound<'_, C, S>
+where
+    C: SerializeTupleVariant,
+    S: Serializer<Ok = C::Ok, Error = C::Error>,
+{
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        match &mut self.state {
+            State::Forward(inner) => inner.serialize_field(&self.ctx.wrap(value)),
+            State::Elided(_) => Ok(()),
+        }
+    }
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        match self.state {
+            State::Forward(inner) => inner.end(),
+            State::Elided(serializer) => Self::end_elided(serializer),
+        }
+    }
+}
+
+impl<C, S> SerializeMap for Compound<'_, C, S>
+where
+    C: SerializeMap,
+    S: Serializer<Ok = C::Ok, Error = C::Error>,
+{
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Self::Error> {
+        let State::Forward(inner) = &mut self.state else {
+            return Ok(());
+        };
+        // Keys that aren't strings (numbers, etc.) never match
+        self.redact_next_value = key
+            .serialize(KeyMatcher(self.ctx.sanitizer))
+            .unwrap_or(false);
+        inner.serialize_key(key)
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        let State::Forward(inner) = &mut self.state else {
+            return Ok(());
+        };
+        if std::mem::take(&mut self.redact_next_value) {
+            inner.serialize_value(&self.ctx.redacted(value))
+        } else {
+            inner.serialize_value(&self.ctx.wrap(value))
+        }
+    }
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        match self.state {
+            State::Forward(inner) => inner.end(),
+            State::Elided(serializer) => Self::end_elided(serializer),
+        }
+    }
+}
+
+impl<C, S> SerializeStruct for Compound<'_, C, S>
+where
+    C: SerializeStruct,
+    S: Serializer<Ok = C::Ok, Error = C::Error>,
+{
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        let State::Forward(inner) = &mut self.state else {
+            return Ok(());
+        };
+        if self.ctx.sanitizer.should_redact(key) {
+            inner.serialize_field(key, &self.ctx.redacted(value))
+        } else {
+            inner.serialize_field(key, &self.ctx.wrap(value))
+        }
+    }
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Self::Error> {
+        match &mut self.state {
+            State::Forward(inner) => inner.skip_field(key),
+            State::Elided(_) => Ok(()),
+        }
+    }
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        match self.state {
+            State::Forward(inner) => inner.end(),
+            State::Elided(serializer) => Self::end_elided(serializer),
+        }
+    }
+}
+
+impl<C, S> SerializeStructVariant for Compound<'_, C, S>
+where
+    C: SerializeStructVariant,
+    S: Serializer<Ok = C::Ok, Error = C::Error>,
+{
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        let State::Forward(inner) = &mut self.state else {
+            return Ok(());
+        };
+        if self.ctx.sanitizer.should_redact(key) {
+            inner.serialize_field(key, &self.ctx.redacted(value))
+        } else {
+            inner.serialize_field(key, &self.ctx.wrap(value))
+        }
+    }
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Self::Error> {
+        match &mut self.state {
+            State::Forward(inner) => inner.skip_field(key),
+            State::Elided(_) => Ok(()),
+        }
+    }
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        match self.state {
+            State::Forward(inner) => inner.end(),
+            State::Elided(serializer) => Self::end_elided(serializer),
+        }
+    }
+}
+
+// Checks a map key against the redaction rules without allocating it.
+// Anything that isn't a string errors out and is treated as "no match"
+struct KeyMatcher<'a>(&'a Sanitizer);
+
+impl KeyMatcher<'_> {
+    fn not_a_string<T>() -> Result<T, serde_json::Error> {
+        Err(ser::Error::custom("not a string key"))
+    }
+}
+
+impl Serializer for KeyMatcher<'_> {
+    type Ok = bool;
+    type Error = serde_json::Error;
+    type SerializeSeq = Impossible<bool, serde_json::Error>;
+    type SerializeTuple = Impossible<bool, serde_json::Error>;
+    type SerializeTupleStruct = Impossible<bool, serde_json::Error>;
+    type SerializeTupleVariant = Impossible<bool, serde_json::Error>;
+    type SerializeMap = Impossible<bool, serde_json::Error>;
+    type SerializeStruct = Impossible<bool, serde_json::Error>;
+    type SerializeStructVariant = Impossible<bool, serde_json::Error>;
+
+    fn serialize_str(self, v: &str) -> Result<bool, Self::Error> {
+        Ok(self.0.should_redact(v))
+    }
+    fn serialize_char(self, v: char) -> Result<bool, Self::Error> {
+        Ok(self.0.should_redact(v.encode_utf8(&mut [0; 4])))
+    }
+    // Enum keys serialize as their variant name
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<bool, Self::Error> {
+        Ok(self.0.should_redact(variant))
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<bool, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _v: bool) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_i8(self, _v: i8) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_i16(self, _v: i16) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_i32(self, _v: i32) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_i64(self, _v: i64) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_u8(self, _v: u8) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_u16(self, _v: u16) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_u32(self, _v: u32) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_u64(self, _v: u64) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_f32(self, _v: f32) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_f64(self, _v: f64) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_bytes(self, _v: &[u8]) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_none(self) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_unit(self) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<bool, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Self::not_a_string()
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Self::not_a_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+    use std::collections::BTreeMap;
+
+    fn sanitizer(keys: &[&str], redaction_style: RedactionStyle) -> Sanitizer {
+        Sanitizer::new(
+            keys.iter().map(ToString::to_string).collect(),
+            redaction_style,
+            #[cfg(feature = "scrub")]
+            Vec::new(),
+            Limits::default(),
+        )
+        .unwrap()
+    }
+
+    fn limited(limits: Limits) -> Sanitizer {
+        Sanitizer::new(
+            Vec::new(),
+            RedactionStyle::Mask,
+            #[cfg(feature = "scrub")]
+            Vec::new(),
+            limits,
+        )
+        .unwrap()
+    }
+
+    fn sanitize_truncated<T: Serialize>(value: &T, sanitizer: &Sanitizer) -> (Value, bool) {
+        // Through the byte writer like `Logger::log` does, not `to_value`
+        let truncated = Cell::new(false);
+        let bytes =
+            serde_json::to_vec(&Sanitized::new(value, Some(sanitizer), &truncated)).unwrap();
+        (serde_json::from_slice(&bytes).unwrap(), truncated.get())
+    }
+
+    fn sanitize<T: Serialize>(value: &T, sanitizer: &Sanitizer) -> Value {
+        sanitize_truncated(value, sanitizer).0
+    }
+
+    #[derive(Serialize)]
+    struct Card {
+        card_number: String,
+        last_used: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    enum Payment {
+        Card { card: Card, password: String },
+    }
+
+    #[derive(Serialize)]
+    struct User {
+        name: String,
+        payments: Vec<Payment>,
+        headers: BTreeMap<String, String>,
+    }
+
+    #[test]
+    fn test_redacts_at_any_depth() {
+        let user = User {
+            name: "saul".to_string(),
+            payments: vec![Payment::Card {
+                card: Card {
+                    card_number: "4242424242424242".to_string(),
+                    last_used: Some("today".to_string()),
+                },
+                password: "hunter2".to_string(),
+            }],
+            headers: BTreeMap::from([
+                ("Authorization".to_string(), "Bearer abc".to_string()),
+                ("accept".to_string(), "json".to_string()),
+            ]),
+        };
+
+        let value = sanitize(
+            &user,
+            &sanitizer(
+                &["password", "authorization", "card_number"],
+                RedactionStyle::Mask,
+            ),
+        );
+
+        assert_eq!(
+            value,
+            json!({
+                "name": "saul",
+                "payments": [{"Card": {
+                    "card": {"card_number": REDACTED, "last_used": "today"},
+                    "password": REDACTED,
+                }}],
+                "headers": {"Authorization": REDACTED, "accept": "json"},
+            })
+        );
+    }
+
+    #[test]
+    fn test_redacts_whole_nested_value() {
+        let data = json!({"ssn": {"area": 123, "group": 45}, "other": [1, {"ssn": "x"}]});
+
+        let value = sanitize(&data, &sanitizer(&["ssn"], RedactionStyle::Mask));
+
+        assert_eq!(
+            value,
+            json!({"ssn": REDACTED, "other": [1, {"ssn": REDACTED}]})
+        );
+    }
+
+    #[test]
+    fn test_hash_is_stable_and_hides_value() {
+        let sanitizer = sanitizer(&["password"], RedactionStyle::Hash);
+
+        let first = sanitize(&json!({"password": "hunter2"}), &sanitizer);
+        let second = sanitize(&json!({"password": "hunter2"}), &sanitizer);
+        let other = sanitize(&json!({"password": "hunter3"}), &sanitizer);
+
+        let hashed = first["password"].as_str().unwrap();
+        assert!(hashed.starts_with("hash:"));
+        assert_eq!(hashed.len(), "hash:".len() + 16);
+        assert!(!hashed.contains("hunter2"));
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn test_fnv1a_known_value() {
+        use std::io::Write;
+        let mut hasher = Fnv1a::default();
+        hasher.write_all(b"a").unwrap();
+        assert_eq!(hasher.0, 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_non_string_keys_pass_through() {
+        let data = BTreeMap::from([(1, "one"), (2, "two")]);
+
+        let value = sanitize(&data, &sanitizer(&["1"], RedactionStyle::Mask));
+
+        assert_eq!(value, json!({"1": "one", "2": "two"}));
+    }
+
+    #[test]
+    fn test_no_rules_means_no_sanitizer() {
+        assert!(
+            Sanitizer::new(
+                Vec::new(),
+                RedactionStyle::Mask,
+                #[cfg(feature = "scrub")]
+                Vec::new(),
+                Limits::default(),
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn test_truncates_long_strings_on_char_boundary() {
+        let sanitizer = limited(Limits {
+            string_len: Some(4),
+            ..Limits::default()
+        });
+
+        let (value, truncated) = sanitize_truncated(
+            &json!({"a": "abcdef", "b": "hééllo", "c": "ok"}),
+            &sanitizer,
+        );
+
+        assert!(truncated);
+        assert_eq!(
+            value,
+            json!({
+                "a": "abcd…(truncated 2 bytes)",
+                // `é` is 2 bytes and would be split at 4
+                "b": "hé…(truncated 5 bytes)",
+                "c": "ok",
+            })
+        );
+    }
+
+    #[test]
+    fn test_truncates_long_arrays() {
+        let sanitizer = limited(Limits {
+            array_len: Some(2),
+            ..Limits::default()
+        });
+
+        let (value, truncated) =
+            sanitize_truncated(&json!({"ids": [1, 2, 3, 4, 5], "few": [1]}), &sanitizer);
+
+        assert!(truncated);
+        assert_eq!(
+            value,
+            json!({"ids": [1, 2, "…(truncated 3 items)"], "few": [1]})
+        );
+    }
+
+    #[test]
+    fn test_replaces_containers_past_max_depth() {
+        let sanitizer = limited(Limits {
+            depth: Some(2),
+            ..Limits::default()
+        });
+
+        let (value, truncated) =
+            sanitize_truncated(&json!({"a": {"b": {"c": 1}, "d": [1]}, "e": 1}), &sanitizer);
+
+        assert!(truncated);
+        assert_eq!(
+            value,
+            json!({
+                "a": {
+                    "b": "…(truncated, max depth reached)",
+                    "d": "…(truncated, max depth reached)",
+                },
+                "e": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_within_limits_is_untouched() {
+        let sanitizer = limited(Limits {
+            string_len: Some(10),
+            array_len: Some(10),
+            depth: Some(10),
+        });
+        let data = json!({"a": ["x", {"b": "y"}]});
+
+        let (value, truncated) = sanitize_truncated(&data, &sanitizer);
+
+        assert!(!truncated);
+        assert_eq!(value, data);
+    }
+}
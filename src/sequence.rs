@@ -0,0 +1,145 @@
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// A logger's sequence counter. Lines are encoded with a `0` where the number goes and
+/// it's filled in as the line is written, so the numbers follow the order of the output
+/// and lines that never get written don't use one up.
+pub(crate) struct Sequencer {
+    pub(crate) key: Cow<'static, str>,
+    // `"key":` as it shows up in the encoded line
+    needle: Vec<u8>,
+    pub(crate) next: Arc<AtomicU64>,
+}
+
+/// Where a line's number goes, sent along with the line to whoever writes it
+pub(crate) struct Sequence {
+    next: Arc<AtomicU64>,
+    at: usize,
+}
+
+impl Sequencer {
+    pub(crate) fn new(key: Cow<'static, str>) -> Self {
+        let mut needle = serde_json::to_vec(key.as_ref()).unwrap_or_default();
+        needle.push(b':');
+        Sequencer {
+            key,
+            needle,
+            next: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Finds the placeholder in a line that was encoded with a sequence number
+    pub(crate) fn locate(&self, line: &[u8]) -> Option<Sequence> {
+        // Only the timestamp, level and message come before it. A key's closing quote can't
+        // show up unescaped inside a string, so the first match is the key itself
+        let after_key = line
+            .windows(self.needle.len())
+            .position(|window| window == self.needle)?
+            + self.needle.len();
+        // `pretty` puts a space after the colon
+        let at = line
+            .get(after_key..)?
+            .iter()
+            .position(|byte| *byte != b' ')
+            .map(|offset| after_key + offset)
+            .filter(|at| line.get(*at) == Some(&b'0'))?;
+        Some(Sequence {
+            next: Arc::clone(&self.next),
+            at,
+        })
+    }
+}
+
+/// Writes `line` to `out`, taking the next number for it if it has a sequence.
+/// Callers write one line at a time, so numbers are taken in the order lines are written
+pub(crate) fn write_line(
+    out: &mut (impl Write + ?Sized),
+    line: &[u8],
+    sequence: Option<&Sequence>,
+) -> io::Result<()> {
+    let Some((sequence, (before, placeholder_and_after))) = sequence.and_then(|sequence| {
+        line.split_at_checked(sequence.at)
+            .map(|parts| (sequence, parts))
+    }) else {
+        return out.write_all(line);
+    };
+    out.write_all(before)?;
+    write!(out, "{}", sequence.next.fetch_add(1, Ordering::Relaxed))?;
+    out.write_all(placeholder_and_after.get(1..).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(sequencer: &Sequencer, line: &str) -> String {
+        let sequence = sequencer.locate(line.as_bytes());
+        let mut out = Vec::new();
+        write_line(&mut out, line.as_bytes(), sequence.as_ref()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_numbers_lines_as_they_are_written() {
+        let sequencer = Sequencer::new("seq".into());
+        let line = r#"{"ts":"t","level":"info","message":"hi","seq":0,"data":{"seq":0}}"#;
+        let first = sequencer.locate(line.as_bytes()).unwrap();
+        let second = sequencer.locate(line.as_bytes()).unwrap();
+
+        let mut out = Vec::new();
+        write_line(&mut out, line.as_bytes(), Some(&second)).unwrap();
+        write_line(&mut out, line.as_bytes(), Some(&first)).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"{"ts":"t","level":"info","message":"hi","seq":0,"data":{"seq":0}}{"ts":"t","level":"info","message":"hi","seq":1,"data":{"seq":0}}"#
+        );
+        assert_eq!(sequencer.next.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_skips_the_key_inside_strings() {
+        let sequencer = Sequencer::new("seq".into());
+        sequencer.next.store(41, Ordering::Relaxed);
+
+        assert_eq!(
+            numbered(
+                &sequencer,
+                r#"{"ts":"t","level":"info","message":"\"seq\":0 \"seq","seq":0}"#
+            ),
+            r#"{"ts":"t","level":"info","message":"\"seq\":0 \"seq","seq":41}"#
+        );
+    }
+
+    #[test]
+    fn test_finds_pretty_placeholders() {
+        let sequencer = Sequencer::new("seq".into());
+
+        assert_eq!(
+            numbered(&sequencer, "{\n  \"message\": \"hi\",\n  \"seq\": 0\n}"),
+            "{\n  \"message\": \"hi\",\n  \"seq\": 0\n}"
+        );
+        assert_eq!(
+            numbered(&sequencer, "{\n  \"message\": \"hi\",\n  \"seq\": 0\n}"),
+            "{\n  \"message\": \"hi\",\n  \"seq\": 1\n}"
+        );
+    }
+
+    #[test]
+    fn test_lines_without_a_placeholder_are_written_as_is() {
+        let sequencer = Sequencer::new("seq".into());
+
+        assert!(sequencer.locate(br#"{"message":"hi"}"#).is_none());
+        assert_eq!(
+            numbered(&sequencer, r#"{"message":"hi"}"#),
+            r#"{"message":"hi"}"#
+        );
+        assert_eq!(sequencer.next.load(Ordering::Relaxed), 0);
+    }
+}
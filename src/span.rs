@@ -0,0 +1,203 @@
+use std::{panic::Location, sync::Arc, time::Instant};
+
+use serde::Serialize;
+
+use crate::{
+    Logger,
+    log_level::LogLevel,
+    logger::LoggerInner,
+    trace::{SpanId, TraceContext, TraceFlags, TraceId},
+};
+
+pub(crate) const TRACE_ID_KEY: &str = "trace_id";
+pub(crate) const SPAN_ID_KEY: &str = "span_id";
+pub(crate) const PARENT_SPAN_ID_KEY: &str = "parent_span_id";
+pub(crate) const TRACE_FLAGS_KEY: &str = "trace_flags";
+pub(crate) const SPAN_KEYS: [&str; 4] = [
+    TRACE_ID_KEY,
+    SPAN_ID_KEY,
+    PARENT_SPAN_ID_KEY,
+    TRACE_FLAGS_KEY,
+];
+
+// Kept so the exit event can be logged once the span's last logger is gone
+struct Exit {
+    inner: Arc<LoggerInner>,
+    level: LogLevel,
+    caller: &'static Location<'static>,
+    started_at: Instant,
+}
+
+/// What a child logger from `Logger::span()` adds to every event
+pub(crate) struct Span {
+    pub(crate) name: String,
+    pub(crate) trace_id: TraceId,
+    pub(crate) id: SpanId,
+    pub(crate) parent_id: Option<SpanId>,
+    // Only known when the trace came from a `TraceContext`, then passed down to child spans
+    pub(crate) flags: Option<TraceFlags>,
+    pub(crate) tracestate: Option<Arc<str>>,
+    exit: Option<Exit>,
+}
+
+// `{"span": "checkout"}` under `data`, plus `duration_ms` on exit
+#[derive(Serialize)]
+struct SpanEvent<'a> {
+    span: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<f64>,
+}
+
+impl Span {
+    /// Starts a span under `parent`, or a new trace if there isn't one
+    pub(crate) fn new(name: String, parent: Option<&Span>) -> Self {
+        Span {
+            name,
+            trace_id: parent.map_or_else(TraceId::random, |parent| parent.trace_id),
+            id: SpanId::random(),
+            parent_id: parent.map(|parent| parent.id),
+            flags: parent.and_then(|parent| parent.flags),
+            tracestate: parent.and_then(|parent| parent.tracestate.clone()),
+            exit: None,
+        }
+    }
+
+    /// The span handling a request with an incoming `traceparent`, a child of the caller's span.
+    /// Each hop gets its own span id, like W3C trace context asks for
+    pub(crate) fn remote(trace_context: &TraceContext) -> Self {
+        Span {
+            name: String::new(),
+            trace_id: trace_context.trace_id,
+            id: SpanId::random(),
+            parent_id: Some(trace_context.parent_id),
+            flags: Some(trace_context.flags),
+            tracestate: trace_context.tracestate.clone(),
+            exit: None,
+        }
+    }
+
+    /// Stands in for a span that lives somewhere else, like OpenTelemetry's active span,
+    /// so events logged in it point at it as is
+    #[cfg(feature = "opentelemetry")]
+    pub(crate) fn attached(trace_context: &TraceContext) -> Self {
+        Span {
+            name: String::new(),
+            trace_id: trace_context.trace_id,
+            id: trace_context.parent_id,
+            parent_id: None,
+            flags: Some(trace_context.flags),
+            tracestate: trace_context.tracestate.clone(),
+            exit: None,
+        }
+    }
+
+    /// The `traceparent` for requests made from within this span
+    pub(crate) fn trace_context(&self) -> TraceContext {
+        TraceContext {
+            trace_id: self.trace_id,
+            parent_id: self.id,
+            flags: self.flags.unwrap_or(TraceFlags::SAMPLED),
+            tracestate: self.tracestate.clone(),
+        }
+    }
+
+    /// Logs the enter event through `logger` and the exit event once the span is dropped
+    pub(crate) fn enter(
+        logger: &Logger,
+        mut span: Span,
+        level: LogLevel,
+        caller: &'static Location<'static>,
+    ) -> Arc<Self> {
+        span.exit = Some(Exit {
+            inner: Arc::clone(&logger.inner),
+            level,
+            caller,
+            started_at: Instant::now(),
+        });
+        let span = Arc::new(span);
+        let data = SpanEvent {
+            span: &span.name,
+            duration_ms: None,
+        };
+        logger.log_in(
+            Some(&span),
+            Some(caller),
+            level,
+            &format!("{} entered", span.name),
+            None,
+            data,
+        );
+        span
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(exit) = self.exit.take() else {
+            return;
+        };
+        let duration = exit.started_at.elapsed();
+        let data = SpanEvent {
+            span: &self.name,
+            duration_ms: Some(duration.as_secs_f64() * 1000.0),
+        };
+        let logger = Logger {
+            inner: exit.inner,
+            span: None,
+        };
+        logger.log_in(
+            Some(self),
+            Some(exit.caller),
+            exit.level,
+            &format!("{} exited", self.name),
+            None,
+            data,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_child_spans_share_the_trace() {
+        let root = Span::new("request".into(), None);
+        let child = Span::new("db".into(), Some(&root));
+
+        assert_eq!(root.parent_id, None);
+        assert_eq!(child.trace_id, root.trace_id);
+        assert_eq!(child.parent_id, Some(root.id));
+        assert_ne!(child.id, root.id);
+    }
+
+    #[test]
+    fn test_remote_span_continues_the_trace() {
+        let trace_context =
+            TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+                .unwrap()
+                .with_tracestate("congo=t61rcWkgMzE");
+        let remote = Span::remote(&trace_context);
+        let child = Span::new("handler".into(), Some(&remote));
+
+        assert_eq!(remote.parent_id, Some(SpanId(0x00f0_67aa_0ba9_02b7)));
+        assert_ne!(remote.id, SpanId(0x00f0_67aa_0ba9_02b7));
+        assert_eq!(child.parent_id, Some(remote.id));
+        assert_eq!(child.flags, Some(TraceFlags(1)));
+
+        let outgoing = child.trace_context();
+        assert_eq!(
+            outgoing.traceparent(),
+            format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", child.id)
+        );
+        assert_eq!(outgoing.tracestate(), Some("congo=t61rcWkgMzE"));
+    }
+
+    #[test]
+    fn test_local_traces_are_sampled() {
+        let root = Span::new("job".into(), None);
+
+        assert_eq!(root.flags, None);
+        assert!(root.trace_context().traceparent().ends_with("-01"));
+    }
+}
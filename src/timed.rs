@@ -0,0 +1,236 @@
+use std::{
+    panic::Location,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{Logger, log_level::LogLevel};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Success,
+    Failure,
+}
+
+// `{"duration_ms": 12.5, "outcome": "success", ...custom_data}` under `data`
+#[derive(Serialize)]
+struct Timing<'a, Data> {
+    duration_ms: f64,
+    outcome: Outcome,
+    #[serde(flatten)]
+    data: &'a Data,
+}
+
+/// Logs how long it was alive for when it's dropped, see `Logger::timed()`.
+///
+/// The end event is Info, Warn when slower than `.warn_after()` and Error when `.fail()` was called
+/// or the thread is panicking.
+#[must_use = "the end event is logged when the timer is dropped, keep it with `let _timer = ...`"]
+pub struct Timer<Data: Serialize> {
+    logger: Logger,
+    name: String,
+    caller: &'static Location<'static>,
+    data: Data,
+    started_at: Instant,
+    warn_after: Option<Duration>,
+    failed: bool,
+}
+
+impl<Data: Serialize> Timer<Data> {
+    pub(crate) fn new(
+        logger: Logger,
+        name: String,
+        caller: &'static Location<'static>,
+        data: Data,
+    ) -> Self {
+        Timer {
+            logger,
+            name,
+            caller,
+            data,
+            started_at: Instant::now(),
+            warn_after: None,
+            failed: false,
+        }
+    }
+
+    /// Also logs `"{name} started"` at Info with `custom_data` right away
+    pub fn log_start(self) -> Self {
+        self.logger.log_from(
+            Some(self.caller),
+            LogLevel::Info,
+            &format!("{} started", self.name),
+            None,
+            &self.data,
+        );
+        self
+    }
+
+    /// Logs the end event at Warn instead of Info when the operation took longer than `threshold`
+    pub fn warn_after(mut self, threshold: Duration) -> Self {
+        self.warn_after = Some(threshold);
+        self
+    }
+
+    /// Marks the operation as failed, the end event is logged at Error with `"outcome": "failure"`
+    pub fn fail(&mut self) {
+        self.failed = true;
+    }
+
+    /// How long it's been since the timer started
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    fn outcome(&self, elapsed: Duration) -> (LogLevel, Outcome) {
+        if self.failed || std::thread::panicking() {
+            (LogLevel::Error, Outcome::Failure)
+        } else if self.warn_after.is_some_and(|threshold| elapsed > threshold) {
+            (LogLevel::Warn, Outcome::Success)
+        } else {
+            (LogLevel::Info, Outcome::Success)
+        }
+    }
+}
+
+impl<Data: Serialize> Drop for Timer<Data> {
+    fn drop(&mut self) {
+        let elapsed = self.elapsed();
+        let (level, outcome) = self.outcome(elapsed);
+        let timing = Timing {
+            duration_ms: elapsed.as_secs_f64() * 1000.0,
+            outcome,
+            data: &self.data,
+        };
+        self.logger.log_from(
+            Some(self.caller),
+            level,
+            &format!("{} finished", self.name),
+            None,
+            timing,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LoggerOptions;
+    use serde_json::json;
+
+    #[test]
+    fn test_outcome_levels() {
+        let (options, _captured) = LoggerOptions::default().captured();
+        let logger = options.init();
+
+        let timer = logger.timed("db.query", ());
+        assert_eq!(
+            timer.outcome(Duration::from_secs(5)),
+            (LogLevel::Info, Outcome::Success)
+        );
+
+        let mut timer = timer.warn_after(Duration::from_millis(100));
+        assert_eq!(
+            timer.outcome(Duration::from_millis(50)),
+            (LogLevel::Info, Outcome::Success)
+        );
+        assert_eq!(
+            timer.outcome(Duration::from_millis(150)),
+            (LogLevel::Warn, Outcome::Success)
+        );
+
+        timer.fail();
+        assert_eq!(
+            timer.outcome(Duration::from_millis(50)),
+            (LogLevel::Error, Outcome::Failure)
+        );
+    }
+
+    #[test]
+    fn test_logs_the_end_event() {
+        let (options, captured) = LoggerOptions::default().synchronous(true).captured();
+        let logger = options.init();
+
+        drop(
+            logger
+                .timed("db.query", json!({"table": "users"}))
+                .log_start(),
+        );
+
+        let lines = captured.lines();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["message"], "db.query started");
+        assert_eq!(lines[0]["data"], json!({"table": "users"}));
+        assert_eq!(lines[1]["message"], "db.query finished");
+        assert_eq!(lines[1]["level"], "info");
+        assert!(lines[1]["data"]["duration_ms"].as_f64().unwrap() >= 0.0);
+        assert_eq!(lines[1]["data"]["outcome"], "success");
+        assert_eq!(lines[1]["data"]["table"], "users");
+    }
+
+    #[test]
+    fn test_slow_and_failed_end_events() {
+        let (options, captured) = LoggerOptions::default().synchronous(true).captured();
+        let logger = options.init();
+
+        let slow = logger.timed("slow", ()).warn_after(Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(5));
+        drop(slow);
+        let mut failed = logger.timed("failed", ());
+        failed.fail();
+        drop(failed);
+
+        let lines = captured.lines();
+        assert_eq!(lines[0]["message"], "slow finished");
+        assert_eq!(lines[0]["level"], "warn");
+        assert!(lines[0]["data"]["duration_ms"].as_f64().unwrap() >= 5.0);
+        assert_eq!(lines[0]["data"]["outcome"], "success");
+        assert_eq!(lines[1]["message"], "failed finished");
+        assert_eq!(lines[1]["level"], "error");
+        assert_eq!(lines[1]["data"]["outcome"], "failure");
+    }
+
+    #[test]
+    fn test_timing_flattens_custom_data() {
+        let data = json!({"table": "users"});
+        let timing = Timing {
+            duration_ms: 12.5,
+            outcome: Outcome::Success,
+            data: &data,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&timing).unwrap(),
+            json!({"duration_ms": 12.5, "outcome": "success", "table": "users"})
+        );
+
+        let timing = Timing {
+            duration_ms: 1.0,
+            outcome: Outcome::Failure,
+            data: &(),
+        };
+        assert_eq!(
+            serde_json::to_value(&timing).unwrap(),
+            json!({"duration_ms": 1.0, "outcome": "failure"})
+        );
+    }
+
+    #[test]
+    fn test_points_at_the_caller() {
+        let (options, captured) = LoggerOptions::default()
+            .synchronous(true)
+            .caller_location(true)
+            .captured();
+        let logger = options.init();
+        let line = line!() + 1;
+        let timer = logger.timed("job", ());
+
+        assert_eq!(timer.caller.line(), line);
+        assert_eq!(timer.caller.file(), file!());
+        drop(timer);
+        let lines = captured.lines();
+        assert_eq!(lines[0]["caller"], json!({"file": file!(), "line": line}));
+    }
+}
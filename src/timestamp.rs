@@ -1,44 +1,237 @@
-use chrono::{DateTime, Utc};
+use chrono::{
+    DateTime, Datelike, FixedOffset, Local, Timelike, Utc,
+    format::{Item, StrftimeItems},
+};
 use serde::{Serialize, Serializer};
+use std::{cell::Cell, io::Write};
 
 pub const DEFAULT_TS_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3fZ";
-pub(crate) struct FormattedTimestamp {
+// Same as the default but with the offset in RFC 3339 form (`+09:00`) instead of a literal `Z`
+pub const DEFAULT_TS_FORMAT_WITH_OFFSET: &str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
+
+// `2024-01-15T14:30:00.123Z`
+const CACHED_TS_LEN: usize = 24;
+const CACHED_TS_MILLIS: std::ops::Range<usize> = 20..23;
+
+thread_local! {
+    // The rendered default timestamp for the last second this thread logged in.
+    // Only the millisecond digits change within a second so we only re-render the rest once a second
+    static CACHED_TS: Cell<Option<(i64, [u8; CACHED_TS_LEN])>> = const { Cell::new(None) };
+}
+
+/// Which time zone timestamps are rendered in.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Timezone {
+    /// Coordinated Universal Time. This is the default.
+    #[default]
+    Utc,
+    /// The system's local time zone, offset is looked up per log line so DST changes are respected.
+    Local,
+    /// A fixed offset from UTC. You can parse one from a string, for example `"+09:00".parse()`
+    Fixed(FixedOffset),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum TimestampFormat {
+    /// `DEFAULT_TS_FORMAT` in UTC, rendered from a per-second cache
+    Default,
+    /// Anything else, pre-parsed so each log line doesn't re-parse the format
+    Items(Vec<Item<'static>>),
+}
+
+impl TimestampFormat {
+    /// Parses the strftime format once at init.
+    /// Falls back to the default RFC 3339 format for the time zone when none is provided.
+    pub(crate) fn parse(tz_format: Option<&str>, timezone: Timezone) -> Self {
+        let default_format = if timezone == Timezone::Utc {
+            DEFAULT_TS_FORMAT
+        } else {
+            DEFAULT_TS_FORMAT_WITH_OFFSET
+        };
+        let fmt = tz_format.unwrap_or(default_format);
+
+        if fmt == DEFAULT_TS_FORMAT && timezone == Timezone::Utc {
+            return TimestampFormat::Default;
+        }
+
+        match StrftimeItems::new(fmt).parse_to_owned() {
+            Ok(items) => TimestampFormat::Items(items),
+            Err(parse_error) => panic!(
+                "timestamp_format '{fmt}' is not a valid strftime format: {parse_error}. \
+                See https://docs.rs/chrono/latest/chrono/format/strftime/index.html#specifiers"
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct FormattedTimestamp<'a> {
     pub(crate) dt: DateTime<Utc>,
-    pub(crate) fmt: &'static str,
+    pub(crate) format: &'a TimestampFormat,
+    pub(crate) timezone: Timezone,
 }
 
-impl Serialize for FormattedTimestamp {
+impl Serialize for FormattedTimestamp<'_> {
     // This avoids an allocation when creating the timestamp
     // before sending it through the channel. It streams chrono's Display output
     // through serde into the output buffer/
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.collect_str(&self.dt.format(self.fmt))
+        let items = match self.format {
+            TimestampFormat::Default => {
+                return match cached_default_format(&self.dt) {
+                    Some(rendered) => serializer.serialize_str(rendered.as_str()),
+                    None => serializer.collect_str(&self.dt.format(DEFAULT_TS_FORMAT)),
+                };
+            }
+            TimestampFormat::Items(items) => items.iter(),
+        };
+
+        match self.timezone {
+            Timezone::Utc => serializer.collect_str(&self.dt.format_with_items(items)),
+            Timezone::Local => {
+                serializer.collect_str(&self.dt.with_timezone(&Local).format_with_items(items))
+            }
+            Timezone::Fixed(offset) => {
+                serializer.collect_str(&self.dt.with_timezone(&offset).format_with_items(items))
+            }
+        }
     }
 }
 
-impl FormattedTimestamp {
-    pub(crate) fn new(tz_format: Option<&'static str>) -> Self {
+impl<'a> FormattedTimestamp<'a> {
+    pub(crate) fn new(format: &'a TimestampFormat, timezone: Timezone) -> Self {
         FormattedTimestamp {
             dt: Utc::now(),
-            fmt: tz_format.unwrap_or(DEFAULT_TS_FORMAT),
+            format,
+            timezone,
         }
     }
 }
 
+struct CachedTimestamp([u8; CACHED_TS_LEN]);
+
+impl CachedTimestamp {
+    fn as_str(&self) -> &str {
+        // Only ever filled with ASCII below, fall back to an empty string instead of panicking
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+}
+
+/// Renders `DEFAULT_TS_FORMAT` byte for byte the same as chrono does, but only
+/// goes through chrono's formatting once per second per thread.
+/// Returns `None` for the cases the fixed width layout can't represent
+/// (years outside 0..=9999 and leap seconds) so the caller can fall back to chrono.
+fn cached_default_format(dt: &DateTime<Utc>) -> Option<CachedTimestamp> {
+    let nanos = dt.nanosecond();
+    if !(0..=9999).contains(&dt.year()) || nanos >= 1_000_000_000 {
+        return None;
+    }
+
+    let second = dt.timestamp();
+    let mut rendered = match CACHED_TS.get() {
+        Some((cached_second, rendered)) if cached_second == second => rendered,
+        _ => {
+            let mut rendered = [0u8; CACHED_TS_LEN];
+            write!(
+                rendered.as_mut_slice(),
+                "{}.000Z",
+                dt.format("%Y-%m-%dT%H:%M:%S")
+            )
+            .ok()?;
+            CACHED_TS.set(Some((second, rendered)));
+            rendered
+        }
+    };
+
+    let millis = nanos / 1_000_000;
+    #[allow(clippy::cast_possible_truncation)] // each of these is a single digit
+    let digits = [millis / 100, millis / 10 % 10, millis % 10].map(|digit| b'0' + digit as u8);
+    rendered.get_mut(CACHED_TS_MILLIS)?.copy_from_slice(&digits);
+
+    Some(CachedTimestamp(rendered))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_uses_default_timestamp_format() {
-        let ts: FormattedTimestamp = FormattedTimestamp::new(None);
-        assert_eq!(ts.fmt, DEFAULT_TS_FORMAT)
+        let format = TimestampFormat::parse(None, Timezone::Utc);
+        assert_eq!(format, TimestampFormat::Default);
+
+        // explicitly passing the default format also gets the fast path
+        let format = TimestampFormat::parse(Some(DEFAULT_TS_FORMAT), Timezone::Utc);
+        assert_eq!(format, TimestampFormat::Default);
+    }
+
+    #[test]
+    fn test_cached_default_format_matches_chrono() {
+        let base = Utc::now().timestamp_millis();
+        let samples = [
+            0,
+            1,
+            999,
+            -1,
+            946_684_799_999,     // 1999-12-31T23:59:59.999Z
+            253_402_300_799_999, // 9999-12-31T23:59:59.999Z
+            -62_167_219_200_000, // 0000-01-01T00:00:00.000Z
+            base,
+        ];
+
+        // Step across a few seconds so the cache gets both hits and misses
+        let stepped = (0..5_000).map(|i| base + i * 7);
+        for millis in samples.into_iter().chain(stepped) {
+            let dt = DateTime::from_timestamp_millis(millis).unwrap();
+            let ts = FormattedTimestamp {
+                dt,
+                format: &TimestampFormat::Default,
+                timezone: Timezone::Utc,
+            };
+
+            let expected = format!("\"{}\"", dt.format(DEFAULT_TS_FORMAT));
+            assert_eq!(serde_json::to_string(&ts).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_cached_default_format_truncates_sub_millis() {
+        // 123.999999ms should render as .123 like chrono does, not round up
+        let dt = DateTime::from_timestamp(1_700_000_000, 123_999_999).unwrap();
+        let ts = FormattedTimestamp {
+            dt,
+            format: &TimestampFormat::Default,
+            timezone: Timezone::Utc,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&ts).unwrap(),
+            "\"2023-11-14T22:13:20.123Z\""
+        );
+    }
+
+    #[test]
+    fn test_falls_back_to_chrono_outside_cached_range() {
+        // Year 10000 is wider than the cached layout
+        let dt = DateTime::from_timestamp(253_402_300_800, 5_000_000).unwrap();
+        assert!(cached_default_format(&dt).is_none());
+
+        let ts = FormattedTimestamp {
+            dt,
+            format: &TimestampFormat::Default,
+            timezone: Timezone::Utc,
+        };
+        assert_eq!(
+            serde_json::to_string(&ts).unwrap(),
+            format!("\"{}\"", dt.format(DEFAULT_TS_FORMAT))
+        );
     }
 
     #[test]
     fn test_returns_timestamp() {
         let now = Utc::now();
-        let ts: FormattedTimestamp = FormattedTimestamp::new(None);
+        let format = TimestampFormat::parse(None, Timezone::Utc);
+        let ts: FormattedTimestamp = FormattedTimestamp::new(&format, Timezone::Utc);
         assert!(now.le(&ts.dt));
     }
 
@@ -47,7 +240,8 @@ mod tests {
         // https://docs.rs/chrono/latest/chrono/format/strftime/index.html#specifiers
         let format_output = "%Y-%b-%d-%a-%I-%p";
 
-        let now = FormattedTimestamp::new(Some(format_output));
+        let format = TimestampFormat::parse(Some(format_output), Timezone::Utc);
+        let now = FormattedTimestamp::new(&format, Timezone::Utc);
         let expected = now.dt.format(format_output).to_string();
         let serialized = serde_json::to_string(&now)
             .unwrap()
@@ -55,7 +249,72 @@ mod tests {
             .to_string();
 
         assert_eq!(expected, serialized);
-        assert_ne!(now.fmt, DEFAULT_TS_FORMAT);
         assert_ne!(now.dt.format(DEFAULT_TS_FORMAT).to_string(), expected);
     }
+
+    #[test]
+    fn test_accepts_owned_format() {
+        let format_output = String::from("%Y-%m-%d");
+        let format = TimestampFormat::parse(Some(&format_output), Timezone::Utc);
+        drop(format_output);
+
+        let now = FormattedTimestamp::new(&format, Timezone::Utc);
+        let serialized = serde_json::to_string(&now).unwrap();
+        assert_eq!(
+            serialized.trim_matches('"'),
+            now.dt.format("%Y-%m-%d").to_string()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a valid strftime format")]
+    fn test_rejects_invalid_format() {
+        let _ = TimestampFormat::parse(Some("%Y-%Q"), Timezone::Utc);
+    }
+
+    #[test]
+    fn test_fixed_offset_includes_offset() {
+        let offset: FixedOffset = "+09:00".parse().unwrap();
+        let format = TimestampFormat::parse(None, Timezone::Fixed(offset));
+        let ts = FormattedTimestamp::new(&format, Timezone::Fixed(offset));
+        let expected = ts
+            .dt
+            .with_timezone(&offset)
+            .format(DEFAULT_TS_FORMAT_WITH_OFFSET)
+            .to_string();
+        let serialized = serde_json::to_string(&ts).unwrap();
+
+        assert_eq!(serialized.trim_matches('"'), expected);
+        assert!(expected.ends_with("+09:00"));
+        // Still valid RFC 3339 and pointing at the same instant
+        let parsed = DateTime::parse_from_rfc3339(&expected).unwrap();
+        assert_eq!(parsed.timestamp_millis(), ts.dt.timestamp_millis());
+    }
+
+    #[test]
+    fn test_local_timezone_is_rfc3339() {
+        let format = TimestampFormat::parse(None, Timezone::Local);
+        let ts = FormattedTimestamp::new(&format, Timezone::Local);
+        assert_eq!(
+            format,
+            TimestampFormat::Items(
+                StrftimeItems::new(DEFAULT_TS_FORMAT_WITH_OFFSET)
+                    .parse_to_owned()
+                    .unwrap()
+            )
+        );
+
+        let serialized = serde_json::to_string(&ts).unwrap();
+        let parsed = DateTime::parse_from_rfc3339(serialized.trim_matches('"')).unwrap();
+        assert_eq!(parsed.timestamp_millis(), ts.dt.timestamp_millis());
+    }
+
+    #[test]
+    fn test_custom_format_is_kept_for_non_utc() {
+        let format = TimestampFormat::parse(Some("%Y"), Timezone::Local);
+        assert_eq!(
+            format,
+            TimestampFormat::Items(StrftimeItems::new("%Y").parse_to_owned().unwrap())
+        );
+    }
 }
@@ -0,0 +1,232 @@
+//! W3C trace context (`traceparent`/`tracestate`) for continuing traces across services.
+
+use std::{fmt, sync::Arc};
+
+use serde::{Serialize, Serializer};
+
+use crate::ids;
+
+/// Written as lowercase hex padded to the W3C widths, 32 characters for trace ids and 16 for span ids
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct TraceId(pub(crate) u128);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct SpanId(pub(crate) u64);
+
+impl TraceId {
+    pub(crate) fn random() -> Self {
+        TraceId(u128::from(ids::random_u64()) << 64 | u128::from(ids::random_u64()))
+    }
+}
+
+impl SpanId {
+    pub(crate) fn random() -> Self {
+        SpanId(ids::random_u64())
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+impl fmt::Display for SpanId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl Serialize for TraceId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Serialize for SpanId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// `trace_flags`, written as 2 hex characters like in `traceparent`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct TraceFlags(pub(crate) u8);
+
+impl TraceFlags {
+    pub(crate) const SAMPLED: Self = TraceFlags(1);
+}
+
+impl fmt::Display for TraceFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}", self.0)
+    }
+}
+
+impl Serialize for TraceFlags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A parsed `traceparent` header, plus `tracestate` if there was one.
+///
+/// Attach an incoming one with `Logger::with_trace_context()` so every event carries
+/// `trace_id`, `span_id`, `parent_span_id` and `trace_flags`. Get one for outgoing requests from `Logger::trace_context()`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TraceContext {
+    pub(crate) trace_id: TraceId,
+    pub(crate) parent_id: SpanId,
+    pub(crate) flags: TraceFlags,
+    pub(crate) tracestate: Option<Arc<str>>,
+}
+
+impl TraceContext {
+    /// Parses a `traceparent` header like `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    /// `None` if it isn't valid, in which case the spec says to start a new trace
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut fields = traceparent.trim().split('-');
+        let version = hex_field(fields.next()?, 2)?;
+        let trace_id = hex_field(fields.next()?, 32)?;
+        let parent_id = hex_field(fields.next()?, 16)?;
+        let flags = hex_field(fields.next()?, 2)?;
+
+        // Later versions may add fields after these, version 00 can't
+        let extra_fields = fields.next().is_some();
+        if version == "ff" || (version == "00" && extra_fields) {
+            return None;
+        }
+
+        let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+        let parent_id = u64::from_str_radix(parent_id, 16).ok()?;
+        // All zeros means invalid for both ids
+        if trace_id == 0 || parent_id == 0 {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id: TraceId(trace_id),
+            parent_id: SpanId(parent_id),
+            flags: TraceFlags(u8::from_str_radix(flags, 16).ok()?),
+            tracestate: None,
+        })
+    }
+
+    /// Keeps the `tracestate` header that came with the `traceparent` so it's passed on as is
+    #[must_use]
+    pub fn with_tracestate(mut self, tracestate: impl Into<String>) -> Self {
+        let tracestate = tracestate.into();
+        let tracestate = tracestate.trim();
+        self.tracestate = (!tracestate.is_empty()).then(|| tracestate.into());
+        self
+    }
+
+    /// The `traceparent` header value, always version `00`
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.parent_id, self.flags)
+    }
+
+    /// The `tracestate` header value to send along, if there is one
+    pub fn tracestate(&self) -> Option<&str> {
+        self.tracestate.as_deref()
+    }
+
+    /// 32 lowercase hex characters
+    pub fn trace_id(&self) -> String {
+        self.trace_id.to_string()
+    }
+
+    /// The span that made the request (or that an outgoing request is made from), 16 lowercase hex characters
+    pub fn span_id(&self) -> String {
+        self.parent_id.to_string()
+    }
+
+    /// Whether the caller recorded this trace
+    pub const fn sampled(&self) -> bool {
+        self.flags.0 & TraceFlags::SAMPLED.0 != 0
+    }
+}
+
+// Exactly `len` lowercase hex digits, uppercase isn't allowed by the spec
+fn hex_field(field: &str, len: usize) -> Option<&str> {
+    (field.len() == len
+        && field
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte)))
+    .then_some(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_ids_are_w3c_width() {
+        assert_eq!(TraceId(1).to_string(), format!("{:0>32}", "1"));
+        assert_eq!(SpanId(0xab).to_string(), "00000000000000ab");
+        assert_eq!(TraceId::random().to_string().len(), 32);
+        assert_eq!(
+            serde_json::to_string(&SpanId(u64::MAX)).unwrap(),
+            "\"ffffffffffffffff\""
+        );
+    }
+
+    #[test]
+    fn test_parses_traceparent() {
+        let trace_context = TraceContext::parse(EXAMPLE).unwrap();
+
+        assert_eq!(trace_context.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(trace_context.span_id(), "00f067aa0ba902b7");
+        assert!(trace_context.sampled());
+        assert_eq!(trace_context.traceparent(), EXAMPLE);
+        assert_eq!(trace_context.tracestate(), None);
+    }
+
+    #[test]
+    fn test_rejects_invalid_traceparent() {
+        let invalid = [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-+bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-600f067aa0ba902b7-01",
+        ];
+        for traceparent in invalid {
+            assert!(TraceContext::parse(traceparent).is_none(), "{traceparent}");
+        }
+    }
+
+    #[test]
+    fn test_accepts_future_versions() {
+        let trace_context = TraceContext::parse(
+            "cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-what-the-future-holds",
+        )
+        .unwrap();
+
+        assert!(!trace_context.sampled());
+        // Sent on as the version we know
+        assert_eq!(
+            trace_context.traceparent(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00"
+        );
+    }
+
+    #[test]
+    fn test_keeps_tracestate() {
+        let trace_context = TraceContext::parse(EXAMPLE)
+            .unwrap()
+            .with_tracestate(" rojo=00f067aa0ba902b7,congo=t61rcWkgMzE ");
+        assert_eq!(
+            trace_context.tracestate(),
+            Some("rojo=00f067aa0ba902b7,congo=t61rcWkgMzE")
+        );
+
+        let trace_context = trace_context.with_tracestate("");
+        assert_eq!(trace_context.tracestate(), None);
+    }
+}
@@ -0,0 +1,685 @@
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use crossbeam_queue::ArrayQueue;
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex, PoisonError},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    collapse::{Collapser, Repeat},
+    logger_options::LoggerOptions,
+    sequence::{self, Sequence},
+};
+
+const OVERSIZED_LOG_PREVIEW_LENGTH: usize = 200; // todo allow override?
+const OVERSIZED_LOG_RESET_WINDOW: Duration = Duration::from_secs(4 * 60 * 60); // todo allow override?
+
+pub(crate) enum WorkerMessage {
+    /// A serialized line, what it takes to collapse it when the logger does that
+    /// and where its sequence number goes when it has one
+    Log(Vec<u8>, Option<Repeat>, Option<Sequence>),
+    /// Write out whatever is batched right away.
+    /// If there's a sender, it's notified once the batch has been written
+    Flush(Option<Sender<()>>),
+    /// Run on the worker once the `Instant` has passed, whatever it writes goes out with the batch
+    Deferred(Instant, DeferredTask),
+}
+
+pub(crate) type DeferredTask = Box<dyn FnOnce(&mut Vec<u8>) + Send>;
+
+/// Where lines end up
+#[derive(Clone)]
+pub(crate) enum Sink {
+    Stderr,
+    #[cfg(test)]
+    Captured(Captured),
+}
+
+impl Sink {
+    fn write(&self, write: impl FnOnce(&mut dyn Write) -> io::Result<()>) {
+        match self {
+            Sink::Stderr => {
+                let mut out = std::io::stderr().lock();
+                let _ = write(&mut out);
+                let _ = out.flush();
+            }
+            #[cfg(test)]
+            Sink::Captured(captured) => {
+                let _ = write(&mut *captured.0.lock().unwrap_or_else(PoisonError::into_inner));
+            }
+        }
+    }
+}
+
+/// Everything written to a `Sink::Captured`, see `LoggerOptions::captured()`
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct Captured(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Captured {
+    /// Every line written so far, parsed
+    pub(crate) fn lines(&self) -> Vec<serde_json::Value> {
+        let written = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        serde_json::Deserializer::from_slice(&written)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+    }
+}
+
+/// Puts used buffers back in the pool, shrinking any that grew past `buffer_pool_max_capacity`
+/// and warning every so often when that happens a lot
+pub(crate) struct BufferRecycler {
+    buffer_pool: Arc<ArrayQueue<Vec<u8>>>,
+    buffer_pool_initial_capacity: usize,
+    buffer_pool_max_capacity: usize,
+    oversized_messages_count: usize,
+    oversized_messages_window: Instant,
+    total_messages_count: usize,
+}
+
+impl BufferRecycler {
+    pub(crate) fn new(
+        buffer_pool: Arc<ArrayQueue<Vec<u8>>>,
+        buffer_pool_initial_capacity: usize,
+        buffer_pool_max_capacity: usize,
+    ) -> Self {
+        BufferRecycler {
+            buffer_pool,
+            buffer_pool_initial_capacity,
+            buffer_pool_max_capacity,
+            oversized_messages_count: 0,
+            oversized_messages_window: Instant::now(),
+            total_messages_count: 0,
+        }
+    }
+
+    pub(crate) fn recycle(&mut self, mut log_buffer: Vec<u8>) {
+        // Reset the window if its expired
+        if self.oversized_messages_window.elapsed() > OVERSIZED_LOG_RESET_WINDOW {
+            self.total_messages_count = 0;
+            self.oversized_messages_count = 0;
+            self.oversized_messages_window = Instant::now();
+        }
+
+        // this is global so that we can give a % of oversized logs
+        self.total_messages_count += 1;
+
+        // Check if the log that just came in made the vec grow
+        // past a certain size and:
+        // 1. Log a warning
+        // 2. trim it down if it did.
+
+        if log_buffer.capacity() > self.buffer_pool_max_capacity {
+            let log_was_oversized = log_buffer.len() > self.buffer_pool_max_capacity;
+
+            if log_was_oversized {
+                self.oversized_messages_count += 1;
+
+                // Check how many in the last N hours
+                // Precision loss only matters past 2^52 logs in the window
+                #[allow(clippy::cast_precision_loss)]
+                let percentage_of_oversized =
+                    self.oversized_messages_count as f64 / self.total_messages_count as f64 * 100.0;
+
+                // Log a warning on first ocurrance or every N (set above)
+                if self.oversized_messages_count == 1
+                    || self
+                        .oversized_messages_count
+                        .is_multiple_of(Writer::warn_every_n(
+                            percentage_of_oversized,
+                            self.total_messages_count,
+                        ))
+                {
+                    // If its an oversized string, we obviously don't want to log the whole thing
+                    let preview_len = log_buffer.len().min(OVERSIZED_LOG_PREVIEW_LENGTH);
+                    let oversized_log_preview = // get the actual text
+                    String::from_utf8_lossy(log_buffer.get(..preview_len).unwrap_or_default());
+
+                    let truncation_note = if log_buffer.len() > OVERSIZED_LOG_PREVIEW_LENGTH {
+                        format!("... ({} bytes total)", log_buffer.len())
+                    } else {
+                        String::new()
+                    };
+                    eprintln!(
+                        "SJL_WARN: You have logs that are greater than your buffer_pool_max_capacity ({} bytes). \
+                    This log was {} bytes. Right now {percentage_of_oversized:.2}% of total logs are oversized. \
+                    Consider increasing the buffer_pool_initial_capacity value if you see this log a lot. \
+                    Log that triggered this: {oversized_log_preview}{truncation_note}",
+                        self.buffer_pool_max_capacity,
+                        log_buffer.len()
+                    )
+                }
+            }
+        }
+
+        // Clear the buffer
+        log_buffer.clear();
+        // This has to come after clear() because shrink_to docs:
+        // `The capacity will remain at least as large as both the length and the supplied value`
+        // So if we shrink first with items still in it, it'll still be the size of the items inside
+        // even though the capacity provided is smaller: max(len(), MAX_BUFFER_POOL_VECTOR_SIZE)
+        // We could also drop the buffer here when it happens, the buffer pool size would shrink
+        // by 1 and the we'd just get new Vec<u8>'s when/if we run out in the producer
+        log_buffer.shrink_to(self.buffer_pool_initial_capacity);
+        // and return it to the pool
+        let _ = self.buffer_pool.push(log_buffer);
+    }
+}
+
+/// The background thread, channel and buffer pool that `Logger`s write through.
+///
+/// Every `.init()` creates its own by default. To have several loggers (say, one per library,
+/// each with their own context and level) share one thread and one ordered output stream,
+/// grab the writer from one of them with `Logger::writer()` and pass it to the others with
+/// `LoggerOptions::writer()`.
+///
+/// Cloning is cheap. The thread is flushed and shut down once the last `Writer` and `Logger` using it is dropped.
+#[derive(Clone)]
+pub struct Writer {
+    pub(crate) inner: Arc<WriterInner>,
+}
+
+pub(crate) struct WriterInner {
+    pub(crate) buffer_pool: Arc<ArrayQueue<Vec<u8>>>,
+    pub(crate) buffer_pool_initial_capacity: usize,
+    pub(crate) output: Output,
+}
+
+pub(crate) enum Output {
+    Worker {
+        sender: Option<Sender<WorkerMessage>>,
+        worker: Option<JoinHandle<()>>,
+    },
+    // Logs are written from the calling thread, the lock keeps lines from interleaving
+    Synchronous(Mutex<BufferRecycler>, Sink),
+}
+
+impl Drop for WriterInner {
+    fn drop(&mut self) {
+        if let Output::Worker { sender, worker } = &mut self.output {
+            // Drop the sender so worker gets Disconnected
+            sender.take();
+
+            // Wait for thread to flush and exit. It can't wait on itself though, which happens when
+            // a deferred task held the last reference to a logger. It exits on its own after that
+            if let Some(worker) = worker.take()
+                && worker.thread().id() != std::thread::current().id()
+            {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+impl Writer {
+    pub(crate) fn new(options: &LoggerOptions) -> Self {
+        // Pre allocate a few buffers into the pool
+        let buffer_pool = Arc::new(ArrayQueue::new(options.buffer_pool_size));
+        for _ in 0..options.buffer_pool_size {
+            let _ = buffer_pool.push(Vec::with_capacity(options.buffer_pool_initial_capacity));
+        }
+
+        let recycler = BufferRecycler::new(
+            Arc::clone(&buffer_pool),
+            options.buffer_pool_initial_capacity,
+            options.buffer_pool_max_capacity,
+        );
+        let output = if options.synchronous {
+            Output::Synchronous(Mutex::new(recycler), options.sink.clone())
+        } else {
+            let (sender, worker) = crossbeam_channel::unbounded::<WorkerMessage>();
+
+            // Run in background
+            let worker = Self::handle_messages(
+                worker,
+                options.sink.clone(),
+                recycler,
+                options.flush_at_bytes,
+                options.flush_at_messages,
+                options.flush_interval,
+            );
+            Output::Worker {
+                sender: Some(sender),
+                worker: Some(worker),
+            }
+        };
+
+        Writer {
+            inner: Arc::new(WriterInner {
+                buffer_pool,
+                buffer_pool_initial_capacity: options.buffer_pool_initial_capacity,
+                output,
+            }),
+        }
+    }
+
+    /// Writes out everything that's been logged so far and waits until it's done.
+    /// Does nothing in synchronous mode since every log is written right away.
+    pub fn flush(&self) {
+        let Output::Worker {
+            sender: Some(sender),
+            worker,
+        } = &self.inner.output
+        else {
+            return;
+        };
+
+        // The worker can't wait on itself, e.g. from a panic hook while the worker is panicking
+        if worker
+            .as_ref()
+            .is_some_and(|worker| worker.thread().id() == std::thread::current().id())
+        {
+            return;
+        }
+
+        let (ack_sender, ack) = crossbeam_channel::bounded(1);
+        if sender.send(WorkerMessage::Flush(Some(ack_sender))).is_ok() {
+            // Errors if the worker is gone, nothing left to wait on then
+            let _ = ack.recv();
+        }
+    }
+
+    /// Asks the worker to write its batch without waiting for it
+    pub(crate) fn request_flush(&self) {
+        if let Output::Worker {
+            sender: Some(sender),
+            ..
+        } = &self.inner.output
+        {
+            let _ = sender.send(WorkerMessage::Flush(None));
+        }
+    }
+
+    /// Runs `task` on the worker once `due` has passed. Does nothing in synchronous mode
+    pub(crate) fn defer(&self, due: Instant, task: DeferredTask) {
+        if let Output::Worker {
+            sender: Some(sender),
+            ..
+        } = &self.inner.output
+        {
+            let _ = sender.send(WorkerMessage::Deferred(due, task));
+        }
+    }
+
+    /// A buffer from the pool instead of creating one each time
+    pub(crate) fn buffer(&self) -> Vec<u8> {
+        let mut buf = self
+            .inner
+            .buffer_pool
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(self.inner.buffer_pool_initial_capacity));
+        buf.clear(); // just in case
+        buf
+    }
+
+    /// Return a buffer that never got written, like when serialization failed
+    pub(crate) fn discard(&self, mut buf: Vec<u8>) {
+        // Extra check, re-clear the buffer before putting it back
+        buf.clear();
+        let _ = self.inner.buffer_pool.push(buf);
+    }
+
+    /// Lines are written on the calling thread, there's no worker
+    pub(crate) fn is_synchronous(&self) -> bool {
+        matches!(self.inner.output, Output::Synchronous(..))
+    }
+
+    /// Hands a serialized log line (newline included) off to be written.
+    /// Repeats are only collapsed on the worker, synchronous mode writes every line
+    pub(crate) fn write(
+        &self,
+        log_buffer: Vec<u8>,
+        repeat: Option<Repeat>,
+        sequence: Option<Sequence>,
+    ) {
+        match &self.inner.output {
+            Output::Worker {
+                sender: Some(sender),
+                ..
+            } => {
+                let _ = sender.send(WorkerMessage::Log(log_buffer, repeat, sequence));
+            }
+            Output::Worker { sender: None, .. } => {}
+            Output::Synchronous(recycler, sink) => {
+                // Holding the lock while writing keeps lines from different threads from interleaving.
+                // A panic mid-write can't leave the recycler in a bad state, so a poisoned lock is fine to reuse
+                let mut recycler = recycler.lock().unwrap_or_else(PoisonError::into_inner);
+
+                sink.write(|out| sequence::write_line(out, &log_buffer, sequence.as_ref()));
+
+                recycler.recycle(log_buffer);
+            }
+        }
+    }
+
+    fn warn_every_n(pct_oversized: f64, total_messages_count: usize) -> usize {
+        // If 50% of our logs are oversized, give a warning every 1 in 200 logs
+        // small minimum of 1000 to keep noise down right from the beginning
+        match pct_oversized {
+            p if p > 50.0 && total_messages_count > 1000 => 200,
+            p if p > 30.0 && total_messages_count > 1000 => 500,
+            _ => 1000, // Default to 1 in 1000 logs
+        }
+    }
+
+    // `None` if the interval is so large it overflows, in which case we only flush on size/count
+    fn next_flush_deadline(flush_interval: Duration) -> Option<Instant> {
+        Instant::now().checked_add(flush_interval)
+    }
+
+    fn handle_messages(
+        worker: Receiver<WorkerMessage>,
+        sink: Sink,
+        mut recycler: BufferRecycler,
+        flush_at_bytes: usize,
+        flush_at_messages: usize,
+        flush_interval: Duration,
+    ) -> std::thread::JoinHandle<()> {
+        // Spawn a dedicated thread for logs
+        std::thread::spawn(move || {
+            let mut batch = Vec::<u8>::with_capacity(flush_at_bytes);
+            let mut batch_message_count: usize = 0;
+            // When the oldest log in the current batch has to be written by. `None` while the batch is empty
+            let mut flush_deadline: Option<Instant> = None;
+            let mut deferred: Vec<(Instant, DeferredTask)> = Vec::new();
+            let mut collapser = Collapser::default();
+
+            loop {
+                // Waiting on the deadline instead of `recv_timeout(flush_interval)` so a steady
+                // trickle of logs can't keep pushing the timer back
+                let wake_at = deferred
+                    .iter()
+                    .map(|(due, _)| *due)
+                    .chain(flush_deadline)
+                    .chain(collapser.deadline())
+                    .min();
+                let received = wake_at.map_or_else(
+                    || worker.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    |wake_at| worker.recv_deadline(wake_at),
+                );
+
+                match received {
+                    Ok(WorkerMessage::Flush(ack)) => {
+                        collapser.release(&mut batch);
+                        Self::write_batch(&sink, &mut batch);
+                        batch_message_count = 0;
+                        flush_deadline = None;
+
+                        if let Some(ack) = ack {
+                            let _ = ack.send(());
+                        }
+                    }
+                    Ok(WorkerMessage::Log(log_buffer, repeat, sequence)) => {
+                        if flush_deadline.is_none() {
+                            flush_deadline = Self::next_flush_deadline(flush_interval);
+                        }
+                        collapser.write(&log_buffer, repeat, sequence, &mut batch);
+                        batch_message_count += 1; // this resets per batch
+
+                        recycler.recycle(log_buffer);
+
+                        // Happy path, flush logs
+                        if batch_message_count >= flush_at_messages || batch.len() >= flush_at_bytes
+                        {
+                            Self::write_batch(&sink, &mut batch);
+                            batch_message_count = 0;
+                            flush_deadline = None;
+                        }
+                    }
+                    Ok(WorkerMessage::Deferred(due, task)) => deferred.push((due, task)),
+                    // Flush regardless of what happened, we might be shutting down.
+                    // Deferred tasks are dropped, whatever they'd report on is gone by now
+                    Err(RecvTimeoutError::Disconnected) => {
+                        collapser.release(&mut batch);
+                        Self::write_batch(&sink, &mut batch);
+                        break;
+                    }
+                    // A deferred task, held repeats or the batch are due, all handled below
+                    Err(RecvTimeoutError::Timeout) => {}
+                }
+
+                let now = Instant::now();
+                if collapser.deadline().is_some_and(|deadline| deadline <= now)
+                    && collapser.release(&mut batch)
+                    && flush_deadline.is_none()
+                {
+                    flush_deadline = Self::next_flush_deadline(flush_interval);
+                }
+                if deferred.iter().any(|(due, _)| *due <= now) {
+                    let (due, pending) = std::mem::take(&mut deferred)
+                        .into_iter()
+                        .partition::<Vec<_>, _>(|(due, _)| *due <= now);
+                    deferred = pending;
+                    // Whatever the tasks write is a different event, so any run of repeats ends here
+                    collapser.end_run(&mut batch);
+                    let len_before = batch.len();
+                    for (_, task) in due {
+                        task(&mut batch);
+                    }
+                    if batch.len() > len_before && flush_deadline.is_none() {
+                        flush_deadline = Self::next_flush_deadline(flush_interval);
+                    }
+                }
+
+                // Checked after every message too because if logs keep coming in
+                // back to back, recv_deadline never gets a chance to time out
+                if flush_deadline.is_some_and(|deadline| now >= deadline) {
+                    Self::write_batch(&sink, &mut batch);
+                    batch_message_count = 0;
+                    flush_deadline = None;
+                }
+            }
+        })
+    }
+
+    fn write_batch(sink: &Sink, batch: &mut Vec<u8>) {
+        if batch.is_empty() {
+            return;
+        }
+
+        // todo make this configurable
+        sink.write(|out| out.write_all(batch));
+
+        batch.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_warn_every_n_defaults_to_100_when_under_min_count() {
+        // even if almost everything is oversized, if we havent hit am inimum don't log anything
+        assert_eq!(Writer::warn_every_n(99.9999, 500), 1000);
+        assert_eq!(Writer::warn_every_n(99.9999, 1000), 1000)
+    }
+
+    #[test]
+    fn test_warn_every_n_returns_200_when_majority_oversized() {
+        assert_eq!(Writer::warn_every_n(50.1, 1001), 200);
+        assert_eq!(Writer::warn_every_n(75.0, 10_000_000), 200);
+        assert_eq!(Writer::warn_every_n(100.0, 1001), 200);
+    }
+
+    #[test]
+    fn test_warn_every_n_returns_500_when_sorta_oversized() {
+        assert_eq!(Writer::warn_every_n(32.0, 1001), 500);
+        assert_eq!(Writer::warn_every_n(42.0, 10_000_000), 500);
+    }
+
+    #[test]
+    fn test_warn_every_n_handles_nan_default() {
+        assert_eq!(Writer::warn_every_n(f64::NAN, 1001), 1000);
+    }
+
+    #[test]
+    fn test_next_flush_deadline_is_one_interval_out() {
+        let before = Instant::now();
+        let deadline = Writer::next_flush_deadline(Duration::from_millis(250)).unwrap();
+
+        assert!(deadline >= before + Duration::from_millis(250));
+        assert!(deadline <= Instant::now() + Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_next_flush_deadline_handles_huge_intervals() {
+        assert_eq!(Writer::next_flush_deadline(Duration::MAX), None);
+    }
+
+    #[test]
+    fn test_trickle_is_written_within_the_flush_interval() {
+        let flush_interval = Duration::from_millis(100);
+        let (options, captured) = LoggerOptions::default()
+            .flush_interval(flush_interval)
+            .captured();
+        let logger = options.init();
+
+        // Well under the size and count thresholds, and often enough that a timer which
+        // restarted on every message would never go off
+        let opened_at = Instant::now();
+        let mut written_after = None;
+        for i in 0..100 {
+            logger.info("trickle", i);
+            if written_after.is_none() && !captured.lines().is_empty() {
+                written_after = Some(opened_at.elapsed());
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let written_after = written_after.expect("nothing was written while the logs kept coming");
+        assert!(
+            written_after < flush_interval + Duration::from_millis(400),
+            "first batch took {written_after:?}"
+        );
+        assert_eq!(captured.lines()[0]["data"], 0);
+    }
+
+    #[test]
+    fn test_flush_waits_for_the_worker() {
+        let (options, captured) = LoggerOptions::default()
+            .flush_interval(Duration::from_hours(1))
+            .captured();
+        let logger = options.init();
+
+        logger.info("batched", ());
+        // Would hang for an hour if the worker didn't ack the flush
+        logger.flush();
+
+        let lines = captured.lines();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["message"], "batched");
+    }
+
+    #[test]
+    fn test_deferred_tasks_run_on_the_worker_when_due() {
+        let logger = LoggerOptions::default().init();
+        let (sender, ran) = crossbeam_channel::bounded(1);
+        let due = Instant::now() + Duration::from_millis(20);
+
+        logger.inner.writer.defer(
+            due,
+            Box::new(move |_| {
+                let _ = sender.send(Instant::now());
+            }),
+        );
+
+        let ran_at = ran.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(ran_at >= due);
+    }
+
+    #[test]
+    fn test_synchronous_has_no_worker() {
+        let logger = LoggerOptions::default().synchronous(true).init();
+
+        assert!(matches!(
+            logger.inner.writer.inner.output,
+            Output::Synchronous(..)
+        ));
+
+        logger.info("written right away", ());
+        logger.flush();
+    }
+
+    #[test]
+    fn test_synchronous_returns_buffers_to_the_pool() {
+        let logger = LoggerOptions::default()
+            .synchronous(true)
+            .buffer_pool_size(1)
+            .init();
+
+        logger.info("one", ());
+        logger.info("two", ());
+        assert_eq!(logger.inner.writer.inner.buffer_pool.len(), 1);
+    }
+
+    #[test]
+    fn test_synchronous_shrinks_oversized_buffers() {
+        let logger = LoggerOptions::default()
+            .synchronous(true)
+            .buffer_pool_size(1)
+            .buffer_pool_initial_capacity(64)
+            .buffer_pool_max_capacity(128)
+            .init();
+
+        logger.info("big", "x".repeat(1_000));
+        let buffer = logger.inner.writer.inner.buffer_pool.pop().unwrap();
+        assert!(buffer.capacity() <= 128);
+    }
+
+    #[test]
+    fn test_loggers_can_share_a_writer() {
+        let (options, captured) = LoggerOptions::default().context("library", "a").captured();
+        let a = options.init();
+        let b = LoggerOptions::default()
+            .context("library", "b")
+            .min_level(crate::LogLevel::Warn)
+            .writer(&a.writer())
+            .init();
+
+        assert!(Arc::ptr_eq(&a.inner.writer.inner, &b.inner.writer.inner));
+
+        a.info("from a", ());
+        b.info("below b's min_level", ());
+        b.warn("from b", ());
+        a.flush();
+
+        let lines = captured.lines();
+        let written: Vec<(&Value, &Value)> = lines
+            .iter()
+            .map(|line| (&line["message"], &line["library"]))
+            .collect();
+        assert_eq!(
+            written,
+            [
+                (&Value::from("from a"), &Value::from("a")),
+                (&Value::from("from b"), &Value::from("b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shared_writer_outlives_the_logger_it_came_from() {
+        let (options, captured) = LoggerOptions::default().captured();
+        let a = options.init();
+        let writer = a.writer();
+        a.info("from a", ());
+        drop(a);
+
+        let b = LoggerOptions::default().writer(&writer).init();
+        b.info("still works", ());
+        b.flush();
+
+        let messages: Vec<Value> = captured
+            .lines()
+            .iter()
+            .map(|line| line["message"].clone())
+            .collect();
+        assert_eq!(messages, ["from a", "still works"]);
+    }
+}